@@ -12,9 +12,7 @@ pub struct Context {
 
 impl Default for Context {
     fn default() -> Self {
-        let scale = screen_height()
-            .try_into()
-            .expect("floating point cast should not fail");
+        let scale = screen_height().into();
         let offset = na::Vector2::<f32>::new(0.5 * (screen_width() - screen_height()), 0.).cast();
         Self { scale, offset }
     }
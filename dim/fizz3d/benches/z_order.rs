@@ -0,0 +1,65 @@
+//! Measures the speedup from sorting the particles along a Z-order curve, on a dam break with
+//! about 100k particles.
+//!
+//! The particles are shuffled first, to mimic the random memory order which develops over a long
+//! simulation as the fluid mixes. Run with `cargo bench -p fizz3d --bench z_order`.
+
+use fizz3d::base::Range;
+use fizz3d::math::*;
+use fizz3d::sph::{SphParamaters, SphSimulation, SphSimulationBuilder};
+use std::time::{Duration, Instant};
+
+const STEPS: usize = 10;
+
+fn dam_break(reorder_interval: Option<usize>) -> SphSimulation {
+    let params = SphParamaters {
+        domain: Range::new(TV::zeros(), TV::new(2., 1.5, 1.)),
+        delta_time: 2e-3,
+        gravity: TV::ith(1, -9.81),
+        reorder_interval,
+        ..Default::default()
+    };
+    let spacing = 0.5 * params.h;
+    let fluid = TV::new(46., 47., 46.) * spacing;
+    let mut sim = SphSimulationBuilder::new(params)
+        .spacing(spacing)
+        .fill_box(Range::new(TV::zeros(), fluid))
+        .build()
+        .unwrap();
+
+    // A deterministic Fisher-Yates shuffle, using a linear congruential generator.
+    let position = sim.particles.position_mut();
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for i in (1..position.len()).rev() {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        position.swap(i, (state >> 33) as usize % (i + 1));
+    }
+    sim
+}
+
+fn run(name: &str, reorder_interval: Option<usize>) -> Duration {
+    let mut sim = dam_break(reorder_interval);
+    let start = Instant::now();
+    for _ in 0..STEPS {
+        sim.advance_timestep();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:>10}: {} particles, {:.1} ms per step",
+        name,
+        sim.particles.len(),
+        elapsed.as_secs_f64() * 1e3 / STEPS as f64
+    );
+    elapsed
+}
+
+fn main() {
+    let unsorted = run("unsorted", None);
+    let sorted = run("z-order", Some(STEPS));
+    println!(
+        "speedup: {:.2}x",
+        unsorted.as_secs_f64() / sorted.as_secs_f64()
+    );
+}
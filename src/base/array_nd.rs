@@ -1,4 +1,4 @@
-use super::Range;
+use super::{Periodicity, Range};
 use crate::math::*;
 use thiserror::Error;
 
@@ -11,12 +11,16 @@ use thiserror::Error;
 /// unsigned integer underflow or Index out of bounds errors can simply ignore this possibility as
 /// long as the caller passes in an `ArrayNd` with a larger domain (with additional "ghost cells"
 /// filled in).
+///
+/// Alternatively, indexing can wrap around along periodic axes (see [`ArrayNd::set_periodic`]),
+/// so that stencils near one side of the domain read the values on the opposite side.
 #[derive(Default)]
 pub struct ArrayNd<T> {
     data: Vec<T>,
     domain: Range<IV>,
     stride: IV,
     offset: isize,
+    periodicity: Periodicity,
 }
 
 // TODO: figure out a vaguely consistent error handling strategy
@@ -34,20 +38,7 @@ where
     ///
     /// Returns an error if the domain is invalid.
     pub fn zeros(domain: Range<IV>) -> Result<Self, ArrayNdCreationError> {
-        let dim = domain.size();
-        let size = dim
-            .iter()
-            .product::<isize>()
-            .try_into()
-            .map_err(|err| ArrayNdCreationError::InvalidDomain(domain, err))?;
-
-        let stride = calculate_strides(dim);
-        Ok(Self {
-            data: vec![num::Zero::zero(); size],
-            domain,
-            stride,
-            offset: -domain.min.dot(&stride),
-        })
+        Self::from_element(domain, num::Zero::zero())
     }
 
     /// Creates a new multi-dimensional array of zeros with the same domain (and other associated
@@ -58,29 +49,70 @@ where
             domain: other.domain,
             stride: other.stride,
             offset: other.offset,
+            periodicity: other.periodicity,
         }
     }
 }
 
 impl<T> ArrayNd<T> {
+    /// The domain of indices of the array.
+    pub fn domain(&self) -> Range<IV> {
+        self.domain
+    }
+
+    /// The axes along which indexing wraps around.
+    pub fn periodicity(&self) -> Periodicity {
+        self.periodicity
+    }
+
+    /// Makes indexing wrap around the domain along the periodic axes, so that e.g. index
+    /// `domain.min - 1` refers to the element at `domain.max - 1`.
+    pub fn set_periodic(&mut self, periodicity: Periodicity) {
+        self.periodicity = periodicity;
+    }
+
+    /// The position of `idx` in `data`, after wrapping periodic axes, or `None` if it is outside
+    /// of the domain. `offset` shifts `domain.min` to the start of `data`.
+    fn flat_index(&self, idx: IV) -> Option<usize> {
+        let idx = self.periodicity.wrap_index(idx, self.domain);
+        self.domain
+            .contains_half_open(idx)
+            .then(|| (idx.dot(&self.stride) + self.offset) as usize)
+    }
+
+    /// The element at `idx`, or `None` if it is outside of the domain along a non-periodic axis.
     pub fn get(&self, idx: IV) -> Option<&T> {
-        if self.domain.contains_half_open(idx) {
-            Some(&self.data[idx.dot(&self.stride) as usize])
-        } else {
-            None
-        }
+        self.flat_index(idx).and_then(|i| self.data.get(i))
     }
 
+    /// The element at `idx`, or `None` if it is outside of the domain along a non-periodic axis.
     pub fn get_mut(&mut self, idx: IV) -> Option<&mut T> {
-        if self.domain.contains_half_open(idx) {
-            Some(&mut self.data[idx.dot(&self.stride) as usize])
-        } else {
-            None
-        }
+        self.flat_index(idx).and_then(|i| self.data.get_mut(i))
     }
 }
 
 impl<T: Clone> ArrayNd<T> {
+    /// Creates a new multi-dimensional array with every element set to `val`.
+    ///
+    /// Returns an error if the domain is invalid.
+    pub fn from_element(domain: Range<IV>, val: T) -> Result<Self, ArrayNdCreationError> {
+        let dim = domain.size();
+        let size = dim
+            .iter()
+            .product::<isize>()
+            .try_into()
+            .map_err(|err| ArrayNdCreationError::InvalidDomain(domain, err))?;
+
+        let stride = calculate_strides(dim);
+        Ok(Self {
+            data: vec![val; size],
+            domain,
+            stride,
+            offset: -domain.min.dot(&stride),
+            periodicity: Periodicity::NONE,
+        })
+    }
+
     /// Fills every element of the the array with `val`.
     pub fn fill(&mut self, val: T) {
         for i in self.data.iter_mut() {
@@ -107,15 +139,91 @@ fn calculate_strides(cells: IV) -> IV {
 
 impl<T> std::ops::Index<IV> for ArrayNd<T> {
     type Output = T;
+    /// Panics if `idx` is outside of the domain. Use [`ArrayNd::get`] to check instead.
     fn index(&self, idx: IV) -> &Self::Output {
-        debug_assert!(self.domain.contains_half_open(idx));
-        &self.data[idx.dot(&self.stride) as usize]
+        match self.get(idx) {
+            Some(value) => value,
+            None => panic!("{:?} is outside of {}", idx, self.domain),
+        }
     }
 }
 
 impl<T> std::ops::IndexMut<IV> for ArrayNd<T> {
+    /// Panics if `idx` is outside of the domain. Use [`ArrayNd::get_mut`] to check instead.
     fn index_mut(&mut self, idx: IV) -> &mut Self::Output {
-        debug_assert!(self.domain.contains_half_open(idx));
-        &mut self.data[idx.dot(&self.stride) as usize]
+        let domain = self.domain;
+        match self.get_mut(idx) {
+            Some(value) => value,
+            None => panic!("{:?} is outside of {}", idx, domain),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::RangeIterator;
+
+    #[test]
+    fn test_negative_domain() {
+        let domain = Range::new(IV::from_element(-1), IV::from_element(3));
+        let mut array = ArrayNd::from_element(domain, 0).unwrap();
+        for (k, idx) in RangeIterator::new(domain).enumerate() {
+            array[idx] = k;
+        }
+        for (k, idx) in RangeIterator::new(domain).enumerate() {
+            assert_eq!(array.get(idx), Some(&k));
+        }
+        assert_eq!(array.get(IV::from_element(3)), None);
+    }
+
+    #[test]
+    fn test_offset_domain() {
+        // `get` and indexing must subtract `domain.min`, so that the first element of the domain
+        // is stored first. Without the offset, `get` read the wrong elements of arrays whose
+        // domain does not start at zero.
+        let domain = Range::new(IV::from_fn(|a, _| 2 - 3 * a as isize), IV::from_element(5));
+        let mut array = ArrayNd::from_element(domain, 0).unwrap();
+        for (k, idx) in RangeIterator::new(domain).enumerate() {
+            *array.get_mut(idx).unwrap() = k + 1;
+        }
+        assert_eq!(array.data.first(), Some(&1));
+        assert_eq!(array.data.last(), Some(&array.data.len()));
+        for (k, idx) in RangeIterator::new(domain).enumerate() {
+            assert_eq!(array[idx], k + 1);
+        }
+        assert_eq!(array.get(IV::zeros()), None);
+    }
+
+    #[test]
+    fn test_periodic_stencil() {
+        let domain = Range::new(IV::zeros(), IV::from_element(4));
+        let mut array = ArrayNd::from_element(domain, 0).unwrap();
+        array[IV::from_element(3)] = 1;
+        array.set_periodic(Periodicity::ALL);
+
+        // A stencil around the origin sees the opposite corner through the periodic boundaries.
+        let stencil = Range::new(IV::from_element(-1), IV::from_element(2));
+        let sum: i32 = RangeIterator::new(stencil)
+            .map(|offset| array[offset])
+            .sum();
+        assert_eq!(sum, 1);
+        assert_eq!(array.get(IV::from_element(-5)), Some(&1));
+
+        array.set_periodic(Periodicity::axis(0));
+        assert_eq!(array.get(IV::from_element(-1)), None);
+        assert_eq!(array.get_mut(IV::from_element(-1)), None);
+        assert_eq!(
+            array.get(IV::from_fn(|a, _| if a == 0 { -1 } else { 3 })),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "is outside of")]
+    fn test_index_outside_domain() {
+        let domain = Range::new(IV::zeros(), IV::from_element(4));
+        let array = ArrayNd::from_element(domain, 0).unwrap();
+        let _ = array[IV::from_element(4)];
     }
 }
@@ -82,7 +82,7 @@ impl Grid {
                 .component_mul(&self.one_over_dx)
                 .map(T::floor),
         )
-        .expect(&format!("Failed to get cell index for {:?}", x))
+        .unwrap_or_else(|| panic!("Failed to get cell index for {:?}", x))
     }
 
     /// Index of the node to the lower-left
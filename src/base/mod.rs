@@ -2,6 +2,8 @@ pub mod array_nd;
 pub mod face_array;
 pub mod grid;
 pub mod grid_iterators;
+pub mod particle_data;
+pub mod periodic;
 pub mod range;
 pub mod vec_ext;
 
@@ -9,5 +11,7 @@ pub use array_nd::ArrayNd;
 pub use face_array::{FaceArray, FaceIndex};
 pub use grid::Grid;
 pub use grid_iterators::RangeIterator;
+pub use particle_data::{Attribute, AttributeValue, ParticleData, ParticleDataError};
+pub use periodic::{PeriodicDomain, Periodicity};
 pub use range::Range;
 pub use vec_ext::{IntoVec, VecExtPartialOrd};
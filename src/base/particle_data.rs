@@ -0,0 +1,592 @@
+use std::any::{type_name, Any};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use thiserror::Error;
+
+/// Any type which can be stored as a per-particle attribute.
+pub trait AttributeValue: Clone + Send + Sync + 'static {}
+
+impl<X: Clone + Send + Sync + 'static> AttributeValue for X {}
+
+/// A typed handle to a named attribute, for example
+/// `const TEMPERATURE: Attribute<T> = Attribute::new("temperature");`.
+pub struct Attribute<X> {
+    name: &'static str,
+    _marker: PhantomData<fn() -> X>,
+}
+
+impl<X> Attribute<X> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<X> Clone for Attribute<X> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<X> Copy for Attribute<X> {}
+
+impl<X> fmt::Debug for Attribute<X> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Attribute<{}>({:?})", type_name::<X>(), self.name)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParticleDataError {
+    #[error("The particles have no attribute named {0:?}.")]
+    MissingAttribute(&'static str),
+    #[error("The particles already have an attribute named {0:?}.")]
+    DuplicateAttribute(&'static str),
+    #[error("The attribute {0:?} cannot be borrowed mutably more than once.")]
+    AliasedAttribute(&'static str),
+    #[error("The attribute {name:?} has type {found}, not {expected}.")]
+    TypeMismatch {
+        name: &'static str,
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error(
+        "{found} values were given for the attribute {name:?}, but there are {expected} particles."
+    )]
+    LengthMismatch {
+        name: &'static str,
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// A column of values of one attribute, with the type erased so that columns of different types
+/// can be stored together.
+trait Column: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+    fn type_name(&self) -> &'static str;
+    fn clone_box(&self) -> Box<dyn Column>;
+
+    fn push_default(&mut self);
+    fn swap_remove(&mut self, i: usize);
+    /// Replaces the values with `values[indices[0]], values[indices[1]], ...`.
+    fn gather(&mut self, indices: &[usize]);
+}
+
+struct TypedColumn<X> {
+    values: Vec<X>,
+    /// The value given to new particles.
+    default: X,
+}
+
+impl<X: AttributeValue> Column for TypedColumn<X> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+
+    fn type_name(&self) -> &'static str {
+        type_name::<X>()
+    }
+
+    fn clone_box(&self) -> Box<dyn Column> {
+        Box::new(TypedColumn {
+            values: self.values.clone(),
+            default: self.default.clone(),
+        })
+    }
+
+    fn push_default(&mut self) {
+        self.values.push(self.default.clone());
+    }
+
+    fn swap_remove(&mut self, i: usize) {
+        self.values.swap_remove(i);
+    }
+
+    fn gather(&mut self, indices: &[usize]) {
+        self.values = indices.iter().map(|&i| self.values[i].clone()).collect();
+    }
+}
+
+/// A container of particles with named, typed attributes, stored as one column per attribute.
+///
+/// Every attribute has a value for every particle. New particles are given the default value of
+/// each attribute, which can then be overwritten through [`ParticleData::get_mut`].
+#[derive(Default)]
+pub struct ParticleData {
+    len: usize,
+    columns: BTreeMap<&'static str, Box<dyn Column>>,
+}
+
+impl ParticleData {
+    /// Creates an empty container without any attributes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of particles.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if there are no particles.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The names of all attributes, in alphabetical order.
+    pub fn attribute_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.columns.keys().copied()
+    }
+
+    /// Returns true if there is an attribute called `name`, of any type.
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.columns.contains_key(name)
+    }
+
+    /// Adds an attribute, which is set to `default` for all existing and new particles.
+    pub fn add_attribute<X: AttributeValue>(
+        &mut self,
+        attribute: Attribute<X>,
+        default: X,
+    ) -> Result<(), ParticleDataError> {
+        if self.has_attribute(attribute.name) {
+            return Err(ParticleDataError::DuplicateAttribute(attribute.name));
+        }
+
+        let column = TypedColumn {
+            values: vec![default.clone(); self.len],
+            default,
+        };
+        self.columns.insert(attribute.name, Box::new(column));
+        Ok(())
+    }
+
+    /// Removes an attribute, returning its values.
+    pub fn remove_attribute<X: AttributeValue>(
+        &mut self,
+        attribute: Attribute<X>,
+    ) -> Result<Vec<X>, ParticleDataError> {
+        self.column::<X>(attribute.name)?;
+        let column = self.columns.remove(attribute.name).unwrap();
+        let column = column
+            .into_any()
+            .downcast::<TypedColumn<X>>()
+            .expect("the type was checked above");
+        Ok(column.values)
+    }
+
+    fn column<X: AttributeValue>(
+        &self,
+        name: &'static str,
+    ) -> Result<&TypedColumn<X>, ParticleDataError> {
+        let column = self
+            .columns
+            .get(name)
+            .ok_or(ParticleDataError::MissingAttribute(name))?;
+        column
+            .as_any()
+            .downcast_ref()
+            .ok_or_else(|| type_mismatch::<X>(name, column.as_ref()))
+    }
+
+    /// The values of an attribute for all particles.
+    pub fn get<X: AttributeValue>(
+        &self,
+        attribute: Attribute<X>,
+    ) -> Result<&[X], ParticleDataError> {
+        Ok(&self.column(attribute.name)?.values)
+    }
+
+    /// The values of an attribute for all particles, which can be modified.
+    pub fn get_mut<X: AttributeValue>(
+        &mut self,
+        attribute: Attribute<X>,
+    ) -> Result<&mut [X], ParticleDataError> {
+        let name = attribute.name;
+        let column = self
+            .columns
+            .get_mut(name)
+            .ok_or(ParticleDataError::MissingAttribute(name))?;
+        downcast_mut(name, column.as_mut())
+    }
+
+    /// Moves the values of an attribute out of the container, leaving the column empty, so that
+    /// they can be modified while the rest of the container is borrowed. The values must be put
+    /// back with [`ParticleData::restore`] before particles are added, removed or reordered.
+    pub fn take<X: AttributeValue>(
+        &mut self,
+        attribute: Attribute<X>,
+    ) -> Result<Vec<X>, ParticleDataError> {
+        let name = attribute.name;
+        let column = self
+            .columns
+            .get_mut(name)
+            .ok_or(ParticleDataError::MissingAttribute(name))?;
+        let error = type_mismatch::<X>(name, column.as_ref());
+        let column = column
+            .as_any_mut()
+            .downcast_mut::<TypedColumn<X>>()
+            .ok_or(error)?;
+        Ok(std::mem::take(&mut column.values))
+    }
+
+    /// Puts back the values of an attribute which were moved out with [`ParticleData::take`].
+    /// There must be one value per particle.
+    pub fn restore<X: AttributeValue>(
+        &mut self,
+        attribute: Attribute<X>,
+        values: Vec<X>,
+    ) -> Result<(), ParticleDataError> {
+        let name = attribute.name;
+        if values.len() != self.len {
+            return Err(ParticleDataError::LengthMismatch {
+                name,
+                expected: self.len,
+                found: values.len(),
+            });
+        }
+        let column = self
+            .columns
+            .get_mut(name)
+            .ok_or(ParticleDataError::MissingAttribute(name))?;
+        let error = type_mismatch::<X>(name, column.as_ref());
+        let column = column
+            .as_any_mut()
+            .downcast_mut::<TypedColumn<X>>()
+            .ok_or(error)?;
+        column.values = values;
+        Ok(())
+    }
+
+    /// The values of several different attributes, for example
+    /// `let (position, velocity) = particles.get_many((POSITION, VELOCITY))?;`.
+    pub fn get_many<A: Attributes>(&self, attributes: A) -> Result<A::Refs<'_>, ParticleDataError> {
+        attributes.get(self)
+    }
+
+    /// The values of several different attributes, which can be modified simultaneously. Returns
+    /// an error if the same attribute is requested twice.
+    pub fn get_many_mut<A: Attributes>(
+        &mut self,
+        attributes: A,
+    ) -> Result<A::Muts<'_>, ParticleDataError> {
+        attributes.get_mut(self)
+    }
+
+    /// Iterates over tuples of attribute values, one tuple per particle.
+    pub fn iter<A: Attributes>(
+        &self,
+        attributes: A,
+    ) -> Result<AttributeIter<A::Refs<'_>>, ParticleDataError> {
+        Ok(AttributeIter(attributes.get(self)?))
+    }
+
+    /// Iterates over tuples of mutable attribute values, one tuple per particle.
+    pub fn iter_mut<A: Attributes>(
+        &mut self,
+        attributes: A,
+    ) -> Result<AttributeIter<A::Muts<'_>>, ParticleDataError> {
+        Ok(AttributeIter(attributes.get_mut(self)?))
+    }
+
+    /// Mutable references to the columns with the given names, in the same order.
+    fn columns_mut<const N: usize>(
+        &mut self,
+        names: [&'static str; N],
+    ) -> Result<[(&'static str, &mut dyn Column); N], ParticleDataError> {
+        for (k, &name) in names.iter().enumerate() {
+            if names[..k].contains(&name) {
+                return Err(ParticleDataError::AliasedAttribute(name));
+            }
+            if !self.has_attribute(name) {
+                return Err(ParticleDataError::MissingAttribute(name));
+            }
+        }
+
+        let mut found: [Option<(&'static str, &mut dyn Column)>; N] = [(); N].map(|_| None);
+        for (&name, column) in self.columns.iter_mut() {
+            if let Some(k) = names.iter().position(|&n| n == name) {
+                found[k] = Some((name, column.as_mut()));
+            }
+        }
+        Ok(found.map(|column| column.expect("all names were found above")))
+    }
+
+    /// Adds a particle with the default value of every attribute, and returns its index.
+    pub fn push(&mut self) -> usize {
+        for column in self.columns.values_mut() {
+            column.push_default();
+        }
+        self.len += 1;
+        self.len - 1
+    }
+
+    /// Adds particles with the default value of every attribute, or removes the last particles, so
+    /// that there are `len` particles.
+    pub fn resize(&mut self, len: usize) {
+        if len < self.len {
+            let kept: Vec<usize> = (0..len).collect();
+            self.gather(&kept);
+        }
+        while self.len < len {
+            self.push();
+        }
+    }
+
+    /// Removes particle `i`, replacing it with the last particle.
+    pub fn swap_remove(&mut self, i: usize) {
+        assert!(i < self.len, "particle {} does not exist", i);
+        for column in self.columns.values_mut() {
+            column.swap_remove(i);
+        }
+        self.len -= 1;
+    }
+
+    /// Removes the particles for which `keep(i)` is false, keeping the rest in order.
+    pub fn retain<F: FnMut(usize) -> bool>(&mut self, mut keep: F) {
+        let kept: Vec<usize> = (0..self.len).filter(|&i| keep(i)).collect();
+        self.gather(&kept);
+    }
+
+    /// Replaces the particles with copies of particles `indices[0], indices[1], ...`. This can be
+    /// used to reorder, filter or duplicate particles.
+    pub fn gather(&mut self, indices: &[usize]) {
+        assert!(
+            indices.iter().all(|&i| i < self.len),
+            "the indices must refer to existing particles"
+        );
+        for column in self.columns.values_mut() {
+            column.gather(indices);
+        }
+        self.len = indices.len();
+    }
+}
+
+impl Clone for ParticleData {
+    fn clone(&self) -> Self {
+        Self {
+            len: self.len,
+            columns: self
+                .columns
+                .iter()
+                .map(|(&name, column)| (name, column.clone_box()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for ParticleData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let attributes: BTreeMap<_, _> = self
+            .columns
+            .iter()
+            .map(|(&name, column)| (name, column.type_name()))
+            .collect();
+        f.debug_struct("ParticleData")
+            .field("len", &self.len)
+            .field("attributes", &attributes)
+            .finish()
+    }
+}
+
+fn type_mismatch<X>(name: &'static str, column: &dyn Column) -> ParticleDataError {
+    ParticleDataError::TypeMismatch {
+        name,
+        expected: type_name::<X>(),
+        found: column.type_name(),
+    }
+}
+
+fn downcast_mut<'a, X: AttributeValue>(
+    name: &'static str,
+    column: &'a mut dyn Column,
+) -> Result<&'a mut [X], ParticleDataError> {
+    let error = type_mismatch::<X>(name, column);
+    column
+        .as_any_mut()
+        .downcast_mut::<TypedColumn<X>>()
+        .map(|column| column.values.as_mut_slice())
+        .ok_or(error)
+}
+
+/// A tuple of attribute handles, whose values can be accessed together.
+pub trait Attributes {
+    /// A tuple of slices of the attribute values.
+    type Refs<'a>;
+    /// A tuple of mutable slices of the attribute values.
+    type Muts<'a>;
+
+    fn get(self, particles: &ParticleData) -> Result<Self::Refs<'_>, ParticleDataError>;
+    fn get_mut(self, particles: &mut ParticleData) -> Result<Self::Muts<'_>, ParticleDataError>;
+}
+
+/// Iterates over a tuple of slices of the same length, yielding tuples of elements.
+pub struct AttributeIter<S>(S);
+
+macro_rules! impl_attributes {
+    ($($X:ident $x:ident),+) => {
+        impl<$($X: AttributeValue),+> Attributes for ($(Attribute<$X>,)+) {
+            type Refs<'a> = ($(&'a [$X],)+);
+            type Muts<'a> = ($(&'a mut [$X],)+);
+
+            fn get(self, particles: &ParticleData) -> Result<Self::Refs<'_>, ParticleDataError> {
+                let ($($x,)+) = self;
+                Ok(($(particles.get($x)?,)+))
+            }
+
+            fn get_mut(
+                self,
+                particles: &mut ParticleData,
+            ) -> Result<Self::Muts<'_>, ParticleDataError> {
+                let ($($x,)+) = self;
+                let [$($x,)+] = particles.columns_mut([$($x.name,)+])?;
+                Ok(($(downcast_mut::<$X>($x.0, $x.1)?,)+))
+            }
+        }
+
+        impl<'a, $($X),+> Iterator for AttributeIter<($(&'a [$X],)+)> {
+            type Item = ($(&'a $X,)+);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let ($($x,)+) = &mut self.0;
+                $(let $x = {
+                    let (first, rest) = $x.split_first()?;
+                    *$x = rest;
+                    first
+                };)+
+                Some(($($x,)+))
+            }
+        }
+
+        impl<'a, $($X),+> Iterator for AttributeIter<($(&'a mut [$X],)+)> {
+            type Item = ($(&'a mut $X,)+);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let ($($x,)+) = &mut self.0;
+                $(let $x = {
+                    let (first, rest) = std::mem::take($x).split_first_mut()?;
+                    *$x = rest;
+                    first
+                };)+
+                Some(($($x,)+))
+            }
+        }
+    };
+}
+
+impl_attributes!(A a);
+impl_attributes!(A a, B b);
+impl_attributes!(A a, B b, C c);
+impl_attributes!(A a, B b, C c, D d);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::*;
+
+    const MASS: Attribute<T> = Attribute::new("mass");
+    const POSITION: Attribute<TV> = Attribute::new("position");
+    const PHASE: Attribute<u8> = Attribute::new("phase");
+
+    fn particles() -> ParticleData {
+        let mut particles = ParticleData::new();
+        particles.add_attribute(MASS, 1.).unwrap();
+        particles.add_attribute(POSITION, TV::zeros()).unwrap();
+        for i in 0..5 {
+            let p = particles.push();
+            particles.get_mut(POSITION).unwrap()[p] = TV::from_element(i as T);
+        }
+        particles.add_attribute(PHASE, 2).unwrap();
+        particles
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut particles = particles();
+        assert_eq!(particles.len(), 5);
+        assert_eq!(particles.get(PHASE).unwrap(), &[2; 5]);
+
+        let wrong_type: Attribute<TV> = Attribute::new("mass");
+        assert!(matches!(
+            particles.get(wrong_type),
+            Err(ParticleDataError::TypeMismatch { name: "mass", .. })
+        ));
+        assert_eq!(
+            particles.add_attribute(MASS, 0.),
+            Err(ParticleDataError::DuplicateAttribute("mass"))
+        );
+        assert_eq!(
+            particles.get_many_mut((MASS, MASS)).err(),
+            Some(ParticleDataError::AliasedAttribute("mass"))
+        );
+
+        for (mass, position) in particles.iter_mut((MASS, POSITION)).unwrap() {
+            *mass = position[0] * 10.;
+        }
+        let (mass, phase) = particles.get_many((MASS, PHASE)).unwrap();
+        assert_eq!(mass, &[0., 10., 20., 30., 40.]);
+        assert_eq!(phase.len(), 5);
+
+        assert_eq!(particles.remove_attribute(PHASE).unwrap(), vec![2; 5]);
+        assert_eq!(
+            particles.get(PHASE),
+            Err(ParticleDataError::MissingAttribute("phase"))
+        );
+    }
+
+    #[test]
+    fn test_removal() {
+        let mut particles = particles();
+        particles.swap_remove(1);
+        let positions: Vec<T> = particles
+            .get(POSITION)
+            .unwrap()
+            .iter()
+            .map(|x| x[0])
+            .collect();
+        assert_eq!(positions, [0., 4., 2., 3.]);
+
+        particles.retain(|i| i % 2 == 0);
+        let positions: Vec<T> = particles
+            .iter((POSITION,))
+            .unwrap()
+            .map(|(x,)| x[0])
+            .collect();
+        assert_eq!(positions, [0., 2.]);
+        assert_eq!(particles.get(MASS).unwrap().len(), 2);
+
+        let mut masses = particles.take(MASS).unwrap();
+        masses[1] = 5.;
+        assert_eq!(particles.get(MASS).unwrap(), &[] as &[T]);
+        assert!(matches!(
+            particles.restore(MASS, vec![0.; 3]),
+            Err(ParticleDataError::LengthMismatch { found: 3, .. })
+        ));
+        particles.restore(MASS, masses).unwrap();
+        assert_eq!(particles.get(MASS).unwrap()[1], 5.);
+
+        let clone = particles.clone();
+        particles.gather(&[1, 1, 0]);
+        assert_eq!(particles.len(), 3);
+        assert_eq!(clone.len(), 2);
+        assert_eq!(particles.get(POSITION).unwrap()[2], TV::zeros());
+    }
+}
@@ -0,0 +1,128 @@
+use super::Range;
+use crate::math::*;
+
+/// Which axes of a domain wrap around, so that leaving the domain on one side re-enters it on
+/// the opposite side.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Periodicity(pub [bool; DIM]);
+
+impl Periodicity {
+    /// No axis is periodic.
+    pub const NONE: Self = Self([false; DIM]);
+    /// Every axis is periodic.
+    pub const ALL: Self = Self([true; DIM]);
+
+    /// Only the given axis is periodic.
+    pub fn axis(axis: usize) -> Self {
+        let mut periodic = [false; DIM];
+        periodic[axis] = true;
+        Self(periodic)
+    }
+
+    /// Returns true if `axis` wraps around.
+    pub fn is_periodic(&self, axis: usize) -> bool {
+        self.0[axis]
+    }
+
+    /// Returns true if any axis wraps around.
+    pub fn any(&self) -> bool {
+        self.0.contains(&true)
+    }
+
+    /// Wraps `idx` into `domain` (excluding `domain.max`) along the periodic axes. The other axes
+    /// are left unchanged.
+    pub fn wrap_index(&self, idx: IV, domain: Range<IV>) -> IV {
+        IV::from_fn(|a, _| {
+            if self.0[a] {
+                domain.min[a] + (idx[a] - domain.min[a]).rem_euclid(domain.max[a] - domain.min[a])
+            } else {
+                idx[a]
+            }
+        })
+    }
+}
+
+/// A domain which wraps around along some of its axes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeriodicDomain {
+    pub domain: Range<TV>,
+    pub periodicity: Periodicity,
+}
+
+impl PeriodicDomain {
+    pub fn new(domain: Range<TV>, periodicity: Periodicity) -> Self {
+        Self {
+            domain,
+            periodicity,
+        }
+    }
+
+    /// Wraps `x` into the domain along the periodic axes.
+    pub fn wrap(&self, x: TV) -> TV {
+        if !self.periodicity.any() {
+            return x;
+        }
+
+        let size = self.domain.size();
+        TV::from_fn(|a, _| {
+            if self.periodicity.0[a] {
+                let wrapped = self.domain.min[a] + (x[a] - self.domain.min[a]).rem_euclid(size[a]);
+                // Rounding can give exactly `max` for tiny negative offsets.
+                if wrapped < self.domain.max[a] {
+                    wrapped
+                } else {
+                    self.domain.min[a]
+                }
+            } else {
+                x[a]
+            }
+        })
+    }
+
+    /// The vector from `y` to `x`, using the closest periodic image of `y`.
+    pub fn displacement(&self, x: TV, y: TV) -> TV {
+        let mut r = x - y;
+        if !self.periodicity.any() {
+            return r;
+        }
+
+        let size = self.domain.size();
+        for a in 0..DIM {
+            if self.periodicity.0[a] {
+                r[a] -= size[a] * (r[a] / size[a]).round();
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_periodic_domain() {
+        let domain = PeriodicDomain::new(
+            Range::new(TV::zeros(), TV::from_element(1.)),
+            Periodicity::axis(0),
+        );
+
+        let x = domain.wrap(TV::from_element(1.25));
+        assert!((x[0] - 0.25).abs() < 1e-12);
+        assert_eq!(x[1], 1.25);
+        assert_eq!(domain.wrap(TV::ith(0, -1e-18))[0], 0.);
+
+        let r = domain.displacement(TV::ith(0, 0.05), TV::ith(0, 0.95));
+        assert!((r - TV::ith(0, 0.1)).norm() < 1e-12);
+        let r = domain.displacement(TV::ith(1, 0.05), TV::ith(1, 0.95));
+        assert!((r - TV::ith(1, -0.9)).norm() < 1e-12);
+
+        let cells = Range::new(IV::zeros(), IV::from_element(4));
+        let idx = Periodicity::ALL.wrap_index(IV::from_element(-1), cells);
+        assert_eq!(idx, IV::from_element(3));
+        assert_eq!(
+            Periodicity::NONE.wrap_index(IV::ith(0, 5), cells),
+            IV::ith(0, 5)
+        );
+    }
+}
@@ -18,5 +18,6 @@ pub mod math {
 }
 
 pub mod base;
+pub mod neighbors;
 pub mod sph;
 pub mod util;
@@ -0,0 +1,620 @@
+//! Fixed-radius neighbor search for particle methods.
+//!
+//! Space is divided into cubic cells with the size of the search radius, so the neighbors of a
+//! point can only be in its own cell or the adjacent ones. Each cell is assigned to a bucket, and
+//! the points are sorted by bucket with a counting sort, so that the points of each bucket are
+//! stored contiguously and no memory is allocated per cell. There are two ways of assigning cells
+//! to buckets:
+//!
+//! * A cell list, which covers a bounded domain with one bucket per cell. Points outside the domain
+//!   are placed in the closest cell.
+//! * A compact spatial hash [Ihmsen et al. 2011], which hashes the (unbounded) cell coordinates into
+//!   a table whose size is proportional to the number of points, so the domain can be unbounded.
+//!   Since different cells may share a bucket, the candidates are always filtered by distance.
+//!
+//! Either can wrap around a [`PeriodicDomain`], in which case the cells along periodic axes are
+//! stretched so that a whole number of them fits into the domain, and distances are measured to
+//! the closest periodic image of each point.
+//!
+//! Since building neighbor lists is expensive, [`VerletLists`] cache them for several steps. The
+//! lists are built with the search radius plus a skin, so they stay valid until some point has
+//! moved more than half the skin [Verlet 1967].
+//!
+//! * Ihmsen, M., Akinci, N., Becker, M., & Teschner, M. (2011). A parallel SPH implementation on
+//!   multi-core CPUs. Computer Graphics Forum, 30(1), 99-112.
+//! * Verlet, L. (1967). Computer "experiments" on classical fluids. I. Thermodynamical properties
+//!   of Lennard-Jones molecules. Physical Review, 159(1), 98.
+
+use crate::base::{PeriodicDomain, Periodicity, Range, RangeIterator, VecExtPartialOrd};
+use crate::math::*;
+use crate::util::parallel;
+use smallvec::SmallVec;
+use std::ops::Index;
+use thiserror::Error;
+
+/// Large primes used to hash cell coordinates, from [Teschner et al. 2003].
+const HASH_PRIMES: [isize; 3] = [73856093, 19349663, 83492791];
+
+/// The number of hash table buckets per point.
+const BUCKETS_PER_POINT: usize = 2;
+
+/// The method used to find neighboring particles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum NeighborSearchType {
+    /// A cell list covering the domain.
+    #[default]
+    CellList,
+    /// A compact spatial hash, which does not require a bounded domain.
+    SpatialHash,
+}
+
+#[derive(Error, Debug)]
+pub enum NeighborSearchError {
+    #[error("The search radius must be positive, but it is {0}.")]
+    InvalidRadius(T),
+    #[error("The domain {0} cannot be covered by a cell list.")]
+    InvalidDomain(Range<TV>),
+    #[error("The Verlet list skin must not be negative, but it is {0}.")]
+    InvalidSkin(T),
+}
+
+/// How cells are assigned to buckets.
+#[derive(Clone, Debug)]
+enum Buckets {
+    /// One bucket for each cell of a grid.
+    CellList { cells: IV },
+    /// Cells are hashed into a table, whose size is updated whenever the points are sorted.
+    SpatialHash { table_size: usize },
+}
+
+/// Finds the points within a fixed radius of a query point.
+///
+/// The points are sorted into buckets by [`NeighborSearch::build`], which must be called again
+/// whenever the points move.
+#[derive(Clone, Debug)]
+pub struct NeighborSearch {
+    radius: T,
+    /// The corner of cell zero.
+    origin: TV,
+    /// The size of the cells along each axis, which is at least the radius.
+    cell_size: TV,
+    /// The domain which the points wrap around, if any axis is periodic.
+    periodic: PeriodicDomain,
+    /// The number of cells along each periodic axis.
+    periodic_cells: IV,
+    buckets: Buckets,
+    /// The points in bucket `b` are `sorted[start[b]..start[b + 1]]`.
+    start: Vec<usize>,
+    /// The indices of the points, sorted by bucket.
+    sorted: Vec<usize>,
+}
+
+impl NeighborSearch {
+    /// Creates a cell list covering `domain`, with cells of size `radius`.
+    pub fn cell_list(radius: T, domain: Range<TV>) -> Result<Self, NeighborSearchError> {
+        Self::new(NeighborSearchType::CellList, radius, domain)
+    }
+
+    /// Creates a compact spatial hash with cells of size `radius`.
+    pub fn spatial_hash(radius: T) -> Result<Self, NeighborSearchError> {
+        let domain = Range::new(TV::zeros(), TV::from_element(radius));
+        Self::new(NeighborSearchType::SpatialHash, radius, domain)
+    }
+
+    /// Creates a neighbor search of the given type. The domain is only used by the cell list.
+    pub fn new(
+        kind: NeighborSearchType,
+        radius: T,
+        domain: Range<TV>,
+    ) -> Result<Self, NeighborSearchError> {
+        Self::periodic(kind, radius, PeriodicDomain::new(domain, Periodicity::NONE))
+    }
+
+    /// Creates a neighbor search in a domain which wraps around along its periodic axes, which
+    /// must be at least twice as long as the radius. Points are wrapped into the domain along
+    /// the periodic axes before they are sorted into cells.
+    pub fn periodic(
+        kind: NeighborSearchType,
+        radius: T,
+        domain: PeriodicDomain,
+    ) -> Result<Self, NeighborSearchError> {
+        Self::check_radius(radius)?;
+        let invalid_domain = || NeighborSearchError::InvalidDomain(domain.domain);
+
+        let size = domain.domain.size();
+        let mut cell_size = TV::from_element(radius);
+        let mut periodic_cells = IV::zeros();
+        for a in (0..DIM).filter(|&a| domain.periodicity.is_periodic(a)) {
+            let cells = (size[a] / radius).floor();
+            if cells.is_nan() || cells < 2. || cells > isize::MAX as T {
+                return Err(invalid_domain());
+            }
+            periodic_cells[a] = cells as isize;
+            cell_size[a] = size[a] / cells;
+        }
+
+        let buckets = match kind {
+            NeighborSearchType::CellList => {
+                let cells = na::try_convert::<_, IV>(size.component_div(&cell_size).map(T::ceil))
+                    .filter(|cells| cells.all_gt(&IV::zeros()))
+                    .ok_or_else(invalid_domain)?;
+                let cells = IV::from_fn(|a, _| {
+                    if domain.periodicity.is_periodic(a) {
+                        periodic_cells[a]
+                    } else {
+                        cells[a]
+                    }
+                });
+                cells
+                    .iter()
+                    .try_fold(1usize, |acc, &c| acc.checked_mul(c as usize))
+                    .ok_or_else(invalid_domain)?;
+                Buckets::CellList { cells }
+            }
+            NeighborSearchType::SpatialHash => Buckets::SpatialHash { table_size: 1 },
+        };
+        let num_buckets = match buckets {
+            Buckets::CellList { cells } => cells.iter().product::<isize>() as usize,
+            Buckets::SpatialHash { table_size } => table_size,
+        };
+
+        Ok(Self {
+            radius,
+            origin: domain.domain.min,
+            cell_size,
+            periodic: domain,
+            periodic_cells,
+            buckets,
+            start: vec![0; num_buckets + 1],
+            sorted: Vec::new(),
+        })
+    }
+
+    fn check_radius(radius: T) -> Result<(), NeighborSearchError> {
+        if radius.is_nan() || radius <= 0. {
+            return Err(NeighborSearchError::InvalidRadius(radius));
+        }
+        Ok(())
+    }
+
+    /// The search radius.
+    pub fn radius(&self) -> T {
+        self.radius
+    }
+
+    /// The domain which the points wrap around.
+    pub fn periodic_domain(&self) -> PeriodicDomain {
+        self.periodic
+    }
+
+    /// The coordinates of the cell containing `x`. For a cell list, this is clamped to the grid.
+    fn cell_of(&self, x: TV) -> IV {
+        let x = self.periodic.wrap(x);
+        let cell = (x - self.origin)
+            .component_div(&self.cell_size)
+            .map(|c| c.floor() as isize);
+        let cell = self.wrap_cell(cell);
+
+        match self.buckets {
+            Buckets::CellList { cells } => cell
+                .component_max(&IV::zeros())
+                .component_min(&(cells - IV::from_element(1))),
+            Buckets::SpatialHash { .. } => cell,
+        }
+    }
+
+    /// Wraps the coordinates of a cell along the periodic axes.
+    fn wrap_cell(&self, cell: IV) -> IV {
+        let cells = Range::new(IV::zeros(), self.periodic_cells);
+        self.periodic.periodicity.wrap_index(cell, cells)
+    }
+
+    /// The bucket containing a cell, or `None` if the cell is outside of a cell list.
+    fn bucket_of(&self, cell: IV) -> Option<usize> {
+        match self.buckets {
+            Buckets::CellList { cells } => {
+                if !Range::new(IV::zeros(), cells).contains_half_open(cell) {
+                    return None;
+                }
+                let linear = (0..DIM).fold(0, |acc, a| acc * cells[a] + cell[a]);
+                Some(linear as usize)
+            }
+            Buckets::SpatialHash { table_size } => {
+                let hash = (0..DIM).fold(0, |acc, a| acc ^ cell[a].wrapping_mul(HASH_PRIMES[a]));
+                Some(hash.rem_euclid(table_size as isize) as usize)
+            }
+        }
+    }
+
+    /// Sorts `points` into buckets with a counting sort. Within each bucket, the points are in
+    /// increasing order.
+    pub fn build(&mut self, points: &[TV]) {
+        if let Buckets::SpatialHash { table_size } = &mut self.buckets {
+            *table_size = (BUCKETS_PER_POINT * points.len()).max(1);
+            self.start.resize(*table_size + 1, 0);
+        }
+
+        let keys = parallel::map(points.len(), |i| {
+            self.bucket_of(self.cell_of(points[i]))
+                .expect("points are clamped to the cell list")
+        });
+
+        // Count the points in each bucket, then take the prefix sum to find where each bucket
+        // starts.
+        self.start.fill(0);
+        for &key in &keys {
+            self.start[key + 1] += 1;
+        }
+        for b in 1..self.start.len() {
+            self.start[b] += self.start[b - 1];
+        }
+
+        let mut next = self.start.clone();
+        self.sorted.resize(points.len(), 0);
+        for (i, &key) in keys.iter().enumerate() {
+            self.sorted[next[key]] = i;
+            next[key] += 1;
+        }
+    }
+
+    /// Finds the indices of the `points` within the search radius of `x`. `points` must be the
+    /// points passed to the last call to [`NeighborSearch::build`].
+    pub fn neighbors<'a>(&'a self, points: &'a [TV], x: TV) -> impl Iterator<Item = usize> + 'a {
+        let cell = self.cell_of(x);
+        let range = Range::new(cell, cell + IV::from_element(1)).thickened(1);
+
+        // Neighboring cells may hash to the same bucket, or wrap around to the same cell, and must
+        // only be visited once.
+        let mut buckets: SmallVec<[usize; 27]> = RangeIterator::new(range)
+            .filter_map(|cell| self.bucket_of(self.wrap_cell(cell)))
+            .collect();
+        if matches!(self.buckets, Buckets::SpatialHash { .. }) || self.periodic.periodicity.any() {
+            buckets.sort_unstable();
+            buckets.dedup();
+        }
+
+        let radius2 = self.radius * self.radius;
+        let periodic = self.periodic;
+        buckets
+            .into_iter()
+            .flat_map(move |b| &self.sorted[self.start[b]..self.start[b + 1]])
+            .filter(move |&&i| periodic.displacement(points[i], x).norm_squared() < radius2)
+            .copied()
+    }
+
+    /// Finds the neighbors of every query point among `points`.
+    pub fn neighbor_lists(&self, points: &[TV], queries: &[TV]) -> NeighborLists {
+        let lists: Vec<SmallVec<[usize; 32]>> = parallel::map(queries.len(), |i| {
+            self.neighbors(points, queries[i]).collect()
+        });
+        NeighborLists::from_lists(&lists)
+    }
+}
+
+/// Precomputed neighbor lists, stored contiguously. Indexing gives the neighbors of a point.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NeighborLists {
+    /// The neighbors of point `i` are `indices[offsets[i]..offsets[i + 1]]`.
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+}
+
+impl NeighborLists {
+    fn from_lists<L: AsRef<[usize]>>(lists: &[L]) -> Self {
+        let mut offsets = Vec::with_capacity(lists.len() + 1);
+        offsets.push(0);
+        let mut indices = Vec::new();
+        for list in lists {
+            indices.extend_from_slice(list.as_ref());
+            offsets.push(indices.len());
+        }
+
+        Self { offsets, indices }
+    }
+
+    /// The number of points with neighbor lists.
+    pub fn len(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    /// Returns true if there are no neighbor lists.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total number of neighbors over all points.
+    pub fn total(&self) -> usize {
+        self.indices.len()
+    }
+}
+
+impl Index<usize> for NeighborLists {
+    type Output = [usize];
+
+    fn index(&self, i: usize) -> &[usize] {
+        &self.indices[self.offsets[i]..self.offsets[i + 1]]
+    }
+}
+
+/// Neighbor lists which are reused until the points have moved too far.
+///
+/// The lists contain the candidates within `radius + skin` of each query point, and are filtered
+/// by the actual `radius` when they are used. They stay valid as long as no query point has moved
+/// more than half the skin since they were built, which requires the points to either be the query
+/// points themselves, or to be static.
+#[derive(Clone, Debug)]
+pub struct VerletLists {
+    radius: T,
+    skin: T,
+    candidates: NeighborLists,
+    /// The query points when the lists were last built.
+    reference: Vec<TV>,
+    /// The domain of the neighbor search which the lists were built with.
+    periodic: PeriodicDomain,
+    builds: usize,
+}
+
+impl VerletLists {
+    /// Creates empty lists, which must be built before they are used.
+    pub fn new(radius: T, skin: T) -> Result<Self, NeighborSearchError> {
+        NeighborSearch::check_radius(radius)?;
+        if skin.is_nan() || skin < 0. {
+            return Err(NeighborSearchError::InvalidSkin(skin));
+        }
+
+        Ok(Self {
+            radius,
+            skin,
+            candidates: NeighborLists::default(),
+            reference: Vec::new(),
+            periodic: PeriodicDomain::new(Range::new(TV::zeros(), TV::zeros()), Periodicity::NONE),
+            builds: 0,
+        })
+    }
+
+    /// The radius of the neighborhoods.
+    pub fn radius(&self) -> T {
+        self.radius
+    }
+
+    /// The extra distance included in the lists.
+    pub fn skin(&self) -> T {
+        self.skin
+    }
+
+    /// The number of times the lists have been built.
+    pub fn builds(&self) -> usize {
+        self.builds
+    }
+
+    /// Returns true if the lists are still valid for `queries`.
+    pub fn is_valid(&self, queries: &[TV]) -> bool {
+        let max_displacement = 0.5 * self.skin;
+        self.reference.len() == queries.len()
+            && self.candidates.len() == queries.len()
+            && queries.iter().zip(&self.reference).all(|(&x, &x0)| {
+                self.periodic.displacement(x, x0).norm_squared()
+                    <= max_displacement * max_displacement
+            })
+    }
+
+    /// Forces the lists to be rebuilt, for example after the points are reordered.
+    pub fn invalidate(&mut self) {
+        self.candidates = NeighborLists::default();
+        self.reference.clear();
+    }
+
+    /// Rebuilds the lists. `search` must have been built from `points`, with a radius of at least
+    /// `radius + skin`.
+    pub fn rebuild(&mut self, search: &NeighborSearch, points: &[TV], queries: &[TV]) {
+        debug_assert!(search.radius() >= self.radius + self.skin);
+        self.candidates = search.neighbor_lists(points, queries);
+        self.reference = queries.to_vec();
+        self.periodic = search.periodic_domain();
+        self.builds += 1;
+    }
+
+    /// Finds the `points` within the radius of `x`, the current position of query point `i`.
+    pub fn neighbors<'a>(
+        &'a self,
+        points: &'a [TV],
+        i: usize,
+        x: TV,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let radius2 = self.radius * self.radius;
+        let periodic = self.periodic;
+        self.candidates[i]
+            .iter()
+            .copied()
+            .filter(move |&j| periodic.displacement(points[j], x).norm_squared() < radius2)
+    }
+
+    /// Finds the neighbors of every query point among `points`.
+    pub fn neighbor_lists(&self, points: &[TV], queries: &[TV]) -> NeighborLists {
+        let lists: Vec<SmallVec<[usize; 32]>> = parallel::map(queries.len(), |i| {
+            self.neighbors(points, i, queries[i]).collect()
+        });
+        NeighborLists::from_lists(&lists)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::{PressureSolver, SphParamaters, SphSimulationBuilder};
+
+    /// Finds the neighbors by checking every pair of points.
+    fn brute_force(points: &[TV], x: TV, radius: T) -> Vec<usize> {
+        (0..points.len())
+            .filter(|&i| (points[i] - x).norm_squared() < radius * radius)
+            .collect()
+    }
+
+    /// A deterministic, irregular set of points in `[-1, 2]^DIM`.
+    fn points() -> Vec<TV> {
+        (0..500)
+            .map(|i| TV::from_fn(|a, _| ((i * (7 + 3 * a) + a) % 97) as T / 32. - 1.))
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_brute_force() {
+        let points = points();
+        let radius = 0.15;
+        let domain = Range::new(TV::zeros(), TV::from_element(1.));
+
+        for kind in [
+            NeighborSearchType::CellList,
+            NeighborSearchType::SpatialHash,
+        ] {
+            let mut search = NeighborSearch::new(kind, radius, domain).unwrap();
+            search.build(&points);
+            let lists = search.neighbor_lists(&points, &points);
+            assert_eq!(lists.len(), points.len());
+
+            for (i, &x) in points.iter().enumerate() {
+                let mut found = lists[i].to_vec();
+                found.sort_unstable();
+                assert_eq!(found, brute_force(&points, x, radius), "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_periodic_matches_brute_force() {
+        let domain = PeriodicDomain::new(
+            Range::new(TV::from_element(-1.), TV::from_element(2.)),
+            Periodicity::axis(0),
+        );
+        let points = points();
+        let radius = 0.4;
+
+        for kind in [
+            NeighborSearchType::CellList,
+            NeighborSearchType::SpatialHash,
+        ] {
+            let mut search = NeighborSearch::periodic(kind, radius, domain).unwrap();
+            search.build(&points);
+            for &x in &points {
+                let mut found: Vec<usize> = search.neighbors(&points, x).collect();
+                found.sort_unstable();
+                let expected: Vec<usize> = (0..points.len())
+                    .filter(|&i| domain.displacement(points[i], x).norm() < radius)
+                    .collect();
+                assert_eq!(found, expected, "{:?}", kind);
+            }
+        }
+
+        // The periodic axis must fit at least two cells.
+        let short = PeriodicDomain::new(
+            Range::new(TV::zeros(), TV::from_element(0.7)),
+            Periodicity::ALL,
+        );
+        assert!(NeighborSearch::periodic(NeighborSearchType::CellList, radius, short).is_err());
+    }
+
+    #[test]
+    fn test_periodic_density_is_uniform() {
+        // A lattice filling a fully periodic box has no free surface, so every particle sees the
+        // same neighborhood.
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.2)),
+            periodicity: Periodicity::ALL,
+            gravity: TV::zeros(),
+            pressure_solver: PressureSolver::StateEquation,
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params.clone())
+            .fill_box(params.domain)
+            .build()
+            .unwrap();
+        let initial = sim.particles.position().to_vec();
+        sim.advance_timestep();
+
+        let density = sim.particles.density();
+        let max = density.iter().copied().fold(T::MIN, T::max);
+        let min = density.iter().copied().fold(T::MAX, T::min);
+        assert!((max - min) / max < 1e-9);
+        for (x, x0) in sim.particles.position().iter().zip(&initial) {
+            assert!((x - x0).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_verlet_lists() {
+        let mut points = points();
+        let (radius, skin) = (0.15, 0.05);
+        let domain = Range::new(TV::zeros(), TV::from_element(1.));
+        let mut search = NeighborSearch::cell_list(radius + skin, domain).unwrap();
+        let mut verlet = VerletLists::new(radius, skin).unwrap();
+        assert!(!verlet.is_valid(&points));
+
+        search.build(&points);
+        verlet.rebuild(&search, &points, &points);
+        assert!(verlet.is_valid(&points));
+
+        // Move every point by less than half the skin, in varying directions.
+        for (i, x) in points.iter_mut().enumerate() {
+            *x += TV::from_fn(|a, _| if (i + a) % 3 == 0 { 0.02 } else { -0.01 });
+        }
+        assert!(verlet.is_valid(&points));
+
+        let lists = verlet.neighbor_lists(&points, &points);
+        for (i, &x) in points.iter().enumerate() {
+            let mut found = lists[i].to_vec();
+            found.sort_unstable();
+            assert_eq!(found, brute_force(&points, x, radius));
+        }
+
+        points[0] += TV::from_element(skin);
+        assert!(!verlet.is_valid(&points));
+        assert_eq!(verlet.builds(), 1);
+    }
+
+    #[test]
+    fn test_verlet_lists_in_simulation() {
+        let run = |verlet_skin| {
+            let params = SphParamaters {
+                domain: Range::new(TV::zeros(), TV::from_element(0.3)),
+                delta_time: 4e-3,
+                gravity: TV::ith(1, -9.81),
+                verlet_skin,
+                ..Default::default()
+            };
+            let mut sim = SphSimulationBuilder::new(params)
+                .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+                .boundary_walls()
+                .build()
+                .unwrap();
+            for _ in 0..20 {
+                sim.advance_timestep();
+            }
+            sim
+        };
+
+        let searched = run(None);
+        let cached = run(Some(0.01));
+        assert!(searched.verlet_lists().is_none());
+
+        // Only the order of the neighbors differs, which changes the rounding of the sums.
+        for (x, y) in searched
+            .particles
+            .position()
+            .iter()
+            .zip(cached.particles.position())
+        {
+            assert!((x - y).norm() < 1e-8);
+        }
+        let builds = cached.verlet_lists().unwrap().builds();
+        assert!(builds > 1 && builds < 20, "{}", builds);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let domain = Range::new(TV::zeros(), TV::from_element(1.));
+        assert!(NeighborSearch::cell_list(0., domain).is_err());
+        assert!(NeighborSearch::spatial_hash(T::NAN).is_err());
+        assert!(NeighborSearch::cell_list(0.1, Range::new(TV::zeros(), TV::zeros())).is_err());
+        assert!(VerletLists::new(0.1, -0.01).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use super::multiphase::PHASE;
+use super::particles::SphParticles;
+use super::thermal::TEMPERATURE;
+use super::{
+    Emitter, Obstacle, Sink, SphBoundary, SphParamaters, SphSimulation, SphSimulationError,
+};
+use crate::base::{Range, RangeIterator, VecExtPartialOrd};
+use crate::math::*;
+
+/// Builds an [`SphSimulation`] by filling regions of the domain with fluid.
+///
+/// Particles are placed on a regular lattice with the given `spacing`, and each particle is given
+/// the mass `rest_density * spacing^DIM`, so that the initial density is close to the rest density.
+pub struct SphSimulationBuilder {
+    params: SphParamaters,
+    spacing: T,
+    velocity: TV,
+    phase: usize,
+    temperature: Option<T>,
+    particles: SphParticles,
+    boundary: SphBoundary,
+    obstacles: Vec<Obstacle>,
+    emitters: Vec<Emitter>,
+    sinks: Vec<Sink>,
+}
+
+impl SphSimulationBuilder {
+    /// Creates a new builder. The spacing defaults to half of the smoothing radius.
+    pub fn new(params: SphParamaters) -> Self {
+        let spacing = 0.5 * params.h;
+        Self {
+            params,
+            spacing,
+            velocity: TV::zeros(),
+            phase: 0,
+            temperature: None,
+            particles: SphParticles::default(),
+            boundary: SphBoundary::default(),
+            obstacles: Vec::new(),
+            emitters: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    /// Sets the distance between neighboring particles for subsequent fills.
+    pub fn spacing(mut self, spacing: T) -> Self {
+        self.spacing = spacing;
+        self
+    }
+
+    /// Sets the initial velocity of particles created by subsequent fills.
+    pub fn velocity(mut self, velocity: TV) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    /// Sets the phase of particles created by subsequent fills, in a multiphase simulation. See
+    /// [`SphParamaters::phases`].
+    pub fn phase(mut self, phase: usize) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    /// Sets the temperature of particles created by subsequent fills, with heat transport. By
+    /// default, particles start at the reference temperature.
+    pub fn temperature(mut self, temperature: T) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// The mass of a particle, derived from the rest density of the current phase and the
+    /// spacing.
+    pub fn particle_mass(&self) -> T {
+        self.params.phase_rest_density(self.phase) * self.spacing.powi(DIM as i32)
+    }
+
+    /// Fills a box with particles.
+    pub fn fill_box(self, region: Range<TV>) -> Self {
+        self.fill_region(region, |_| true)
+    }
+
+    /// Fills a circle (in 2d) or sphere (in 3d) with particles.
+    pub fn fill_sphere(self, center: TV, radius: T) -> Self {
+        let region = Range::new(center, center).thickened(radius);
+        self.fill_region(region, |x| (x - center).norm_squared() <= radius * radius)
+    }
+
+    /// Places a particle at each lattice point in `region` for which `inside` returns true.
+    fn fill_region<F: Fn(TV) -> bool>(mut self, region: Range<TV>, inside: F) -> Self {
+        let mass = self.particle_mass();
+        let multiphase = !self.params.phases.is_empty();
+        if multiphase && !self.particles.data().has_attribute(PHASE.name()) {
+            self.particles.data_mut().add_attribute(PHASE, 0).unwrap();
+        }
+        let mut temperature = None;
+        if let Some(thermal) = &self.params.thermal {
+            let attributes = self.particles.data_mut();
+            if !attributes.has_attribute(TEMPERATURE.name()) {
+                attributes
+                    .add_attribute(TEMPERATURE, thermal.reference_temperature)
+                    .unwrap();
+            }
+            temperature = Some(self.temperature.unwrap_or(thermal.reference_temperature));
+        }
+
+        for x in lattice(region, self.spacing) {
+            if inside(x) {
+                self.particles.push(mass, x, self.velocity);
+                if multiphase {
+                    let phases = self.particles.data_mut().get_mut(PHASE).unwrap();
+                    *phases.last_mut().unwrap() = self.phase;
+                }
+                if let Some(temperature) = temperature {
+                    let temperatures = self.particles.data_mut().get_mut(TEMPERATURE).unwrap();
+                    *temperatures.last_mut().unwrap() = temperature;
+                }
+            }
+        }
+
+        self
+    }
+
+    /// Adds boundary walls enclosing the domain, with a thickness of `h`. The sides of periodic
+    /// axes are left open.
+    pub fn boundary_walls(mut self) -> Self {
+        let params = &self.params;
+        self.boundary.sample_periodic_walls(
+            params.domain,
+            params.h,
+            self.spacing,
+            params.periodicity,
+        );
+        self
+    }
+
+    /// Adds a solid box obstacle.
+    pub fn boundary_box(mut self, region: Range<TV>) -> Self {
+        self.boundary.sample_box(region, self.spacing);
+        self
+    }
+
+    /// Adds a solid circle (in 2d) or sphere (in 3d) obstacle.
+    pub fn boundary_sphere(mut self, center: TV, radius: T) -> Self {
+        self.boundary.sample_sphere(center, radius, self.spacing);
+        self
+    }
+
+    /// Adds an obstacle described by a signed distance field.
+    pub fn obstacle(mut self, obstacle: Obstacle) -> Self {
+        self.obstacles.push(obstacle);
+        self
+    }
+
+    /// Adds a source of new particles.
+    pub fn emitter(mut self, emitter: Emitter) -> Self {
+        self.emitters.push(emitter);
+        self
+    }
+
+    /// Adds a region in which particles are removed.
+    pub fn sink(mut self, sink: Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Creates the simulation, setting `num_particles` to the number of particles created.
+    pub fn build(mut self) -> Result<SphSimulation, SphSimulationError> {
+        let phases = self.params.phases.len();
+        if let Some(emitter) = self
+            .emitters
+            .iter()
+            .find(|e| phases > 0 && e.phase >= phases)
+        {
+            return Err(SphSimulationError::InvalidPhase {
+                phase: emitter.phase,
+                phases,
+            });
+        }
+
+        self.params.num_particles = self.particles.len();
+        let mut sim = SphSimulation::with_particles(self.params, self.particles)?;
+        sim.set_boundary(self.boundary);
+        sim.obstacles = self.obstacles;
+        sim.emitters = self.emitters;
+        sim.sinks = self.sinks;
+        Ok(sim)
+    }
+}
+
+/// Returns the points of a lattice with the given spacing which lie in `region`, offset by half
+/// of the spacing from `region.min`.
+pub(super) fn lattice(region: Range<TV>, spacing: T) -> impl Iterator<Item = TV> {
+    // `RangeIterator` always yields its first index, so empty lattices must be skipped here.
+    let count = Some(spacing)
+        .filter(|&spacing| spacing > 0.)
+        .and_then(|spacing| na::try_convert::<_, IV>((region.size() / spacing).map(T::floor)))
+        .filter(|count| count.all_gt(&IV::zeros()));
+
+    count
+        .map(|count| RangeIterator::new(Range::new(IV::zeros(), count)))
+        .into_iter()
+        .flatten()
+        .map(move |idx| region.min + (na::convert::<_, TV>(idx) + TV::from_element(0.5)) * spacing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_box() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(1.)),
+            ..Default::default()
+        };
+        let builder = SphSimulationBuilder::new(params)
+            .spacing(0.1)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.5)));
+        let mass = builder.particle_mass();
+        let sim = builder.build().unwrap();
+
+        assert_eq!(sim.params.num_particles, 5usize.pow(DIM as u32));
+        assert!(sim.particles.mass().iter().all(|&m| m == mass));
+        assert!((mass - 1000. * 0.1f64.powi(DIM as i32)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_particle_count_mismatch() {
+        let params = SphParamaters {
+            num_particles: 3,
+            ..Default::default()
+        };
+        assert!(matches!(
+            SphSimulation::new(params),
+            Err(SphSimulationError::ParticleCountMismatch {
+                expected: 3,
+                found: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn test_resting_block_stays_in_domain() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 1e-3,
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params.clone())
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.2)))
+            .build()
+            .unwrap();
+
+        for _ in 0..10 {
+            sim.advance_timestep();
+        }
+
+        let domain = params.domain.thickened(0.01);
+        assert!(sim.particles.position().iter().all(|&x| domain.contains(x)));
+    }
+}
@@ -0,0 +1,262 @@
+//! Divergence-free SPH (DFSPH), from
+//!
+//! * Bender, J., & Koschier, D. (2015). Divergence-free smoothed particle hydrodynamics. In Proceedings of the 14th ACM SIGGRAPH/Eurographics symposium on computer animation (pp. 147-155).
+//!
+//! DFSPH enforces two conditions in every step: the velocity field should be divergence-free (so
+//! the density does not change over time), and the density of the predicted positions should be
+//! the rest density. Both are enforced by iteratively correcting the velocities with pressure
+//! accelerations, using a per-particle factor `alpha` which only depends on the positions, and can
+//! therefore be computed once per step.
+
+use super::particles::{ALPHA, FORCE, PRESSURE, VELOCITY};
+use super::{SolverStats, SphSimulation};
+use crate::math::*;
+use crate::util::parallel;
+use tracing::{debug, instrument, trace};
+
+/// The minimum number of iterations of the constant density solver.
+const MIN_DENSITY_ITERATIONS: usize = 2;
+
+/// The minimum number of iterations of the divergence-free solver.
+const MIN_DIVERGENCE_ITERATIONS: usize = 1;
+
+/// Denominators of `alpha` smaller than this are treated as zero, to avoid dividing by zero for
+/// particles without any neighbors.
+const ALPHA_EPSILON: T = 1e-6;
+
+impl SphSimulation {
+    /// Computes `alpha_i = rho_i / (|sum_j m_j grad W_ij|^2 + sum_j |m_j grad W_ij|^2)`, which
+    /// relates the density error of a particle to the pressure needed to correct it. The boundary
+    /// particles only contribute to the first sum, since they do not move.
+    #[instrument(skip_all)]
+    pub(super) fn calculate_dfsph_factors(&mut self) {
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let kernel = self.params.pressure_kernel;
+        let boundary_gradients = self.boundary_gradients();
+        let mut alpha = self.particles.take(ALPHA);
+        let position = self.particles.position();
+        let mass = self.particles.mass();
+        let density = self.particles.density();
+
+        parallel::for_each_mut(&mut alpha, |i, alpha| {
+            let x = position[i];
+            let mut sum_gradient = boundary_gradients[i];
+            let mut sum_gradient_squared = 0.;
+            for j in self.get_neighbors(i) {
+                let grad = mass[j] * kernel.gradient(periodic.displacement(x, position[j]), h);
+                sum_gradient += grad;
+                sum_gradient_squared += grad.norm_squared();
+            }
+
+            let denominator = sum_gradient.norm_squared() + sum_gradient_squared;
+            *alpha = if denominator > ALPHA_EPSILON {
+                density[i] / denominator
+            } else {
+                0.
+            };
+        });
+        self.particles.restore(ALPHA, alpha);
+    }
+
+    /// Corrects the velocities so that the density does not change over time.
+    #[instrument(skip_all)]
+    pub(super) fn divergence_solve(&mut self, tolerance: T, max_iterations: usize) -> SolverStats {
+        let dt = self.params.delta_time;
+
+        let mut velocity = self.particles.take(VELOCITY);
+        let stats = self.dfsph_iterate(
+            &mut velocity,
+            tolerance,
+            max_iterations,
+            MIN_DIVERGENCE_ITERATIONS,
+            // The density change over the time step. Only compression is corrected.
+            |_, density_change| (dt * density_change).max(0.),
+        );
+        self.particles.restore(VELOCITY, velocity);
+
+        debug!(?stats, "divergence-free solve");
+        stats
+    }
+
+    /// Corrects the predicted velocities so that the density of the predicted positions is the rest
+    /// density. The corrections are added to the particle forces.
+    ///
+    /// The non-pressure forces must already have been applied.
+    #[instrument(skip_all)]
+    pub(super) fn constant_density_solve(
+        &mut self,
+        tolerance: T,
+        max_iterations: usize,
+    ) -> SolverStats {
+        let n = self.params.num_particles;
+        let dt = self.params.delta_time;
+        let rest_density = self.params.rest_density;
+
+        let particles = &self.particles;
+        let (velocity, force, density) =
+            (particles.velocity(), particles.force(), particles.density());
+        let initial = parallel::map(n, |i| velocity[i] + dt * force[i] / density[i]);
+
+        let mut velocity = initial.clone();
+        let stats = self.dfsph_iterate(
+            &mut velocity,
+            tolerance,
+            max_iterations,
+            MIN_DENSITY_ITERATIONS,
+            // The density error of the predicted position. Only compression is corrected, since
+            // particles at the free surface have incomplete neighborhoods.
+            |density, density_change| (density + dt * density_change - rest_density).max(0.),
+        );
+
+        let mut force = self.particles.take(FORCE);
+        let density = self.particles.density();
+        parallel::for_each_mut(&mut force, |i, force| {
+            *force += density[i] * (velocity[i] - initial[i]) / dt;
+        });
+        self.particles.restore(FORCE, force);
+        self.apply_boundary_reaction();
+
+        stats
+    }
+
+    /// The iteration shared by both DFSPH solvers.
+    ///
+    /// In each iteration, the rate of change of the density `D rho_i / Dt` is computed from
+    /// `velocity`, and `density_error(rho_i, D rho_i / Dt)` gives the density error to correct.
+    /// Each velocity is then corrected by `-dt * sum_j m_j (kappa_i / rho_i + kappa_j / rho_j)
+    /// grad W_ij`, where `kappa_i = alpha_i * error_i / dt^2`, until the average density error
+    /// (relative to the rest density) is below the tolerance.
+    ///
+    /// The pressures corresponding to the total corrections are stored in the particles.
+    fn dfsph_iterate<F>(
+        &mut self,
+        velocity: &mut [TV],
+        tolerance: T,
+        max_iterations: usize,
+        min_iterations: usize,
+        density_error: F,
+    ) -> SolverStats
+    where
+        F: Fn(T, T) -> T + Sync,
+    {
+        let n = self.params.num_particles;
+        if n == 0 {
+            return SolverStats::default();
+        }
+
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let dt = self.params.delta_time;
+        let rest_density = self.params.rest_density;
+        let kernel = self.params.pressure_kernel;
+        let deterministic = self.params.deterministic;
+
+        let neighbors = self.neighbor_lists();
+        let boundary_gradients = self.boundary_gradients();
+        let mut pressure = self.particles.take(PRESSURE);
+        let particles = &self.particles;
+        let mass = particles.mass();
+        let density = particles.density();
+        let position = particles.position();
+        let alpha = particles.alpha();
+
+        let gradients: Vec<Vec<TV>> = parallel::map(n, |i| {
+            neighbors[i]
+                .iter()
+                .map(|&j| kernel.gradient(periodic.displacement(position[i], position[j]), h))
+                .collect()
+        });
+
+        // kappa_i / rho_i for the current iteration, and the sum of kappa_i over all iterations.
+        let mut kappa_over_density = vec![0.; n];
+        let mut total_kappa = vec![0.; n];
+
+        let mut stats = SolverStats::default();
+        loop {
+            let current_velocity = &*velocity;
+            let errors = parallel::map(n, |i| {
+                let density_change: T = neighbors[i]
+                    .iter()
+                    .zip(&gradients[i])
+                    .map(|(&j, &grad)| {
+                        mass[j] * (current_velocity[i] - current_velocity[j]).dot(&grad)
+                    })
+                    .sum::<T>()
+                    + current_velocity[i].dot(&boundary_gradients[i]);
+
+                density_error(density[i], density_change)
+            });
+
+            let kappa = parallel::map(n, |i| alpha[i] * errors[i] / (dt * dt));
+            parallel::for_each_mut(&mut kappa_over_density, |i, k| *k = kappa[i] / density[i]);
+            parallel::for_each_mut(&mut total_kappa, |i, total| *total += kappa[i]);
+
+            let total_error = parallel::sum(&errors, deterministic);
+            stats.density_error = total_error / (n.max(1) as T * rest_density);
+            trace!(stats.iterations, stats.density_error);
+
+            let converged = stats.iterations >= min_iterations && stats.density_error <= tolerance;
+            if converged || stats.iterations >= max_iterations {
+                break;
+            }
+
+            let kappa_over_density = &kappa_over_density;
+            parallel::for_each_mut(velocity, |i, velocity| {
+                let k_i = kappa_over_density[i];
+                *velocity -= dt
+                    * neighbors[i]
+                        .iter()
+                        .zip(&gradients[i])
+                        .map(|(&j, &grad)| mass[j] * (k_i + kappa_over_density[j]) * grad)
+                        .sum::<TV>()
+                    + dt * k_i * boundary_gradients[i];
+            });
+
+            stats.iterations += 1;
+        }
+
+        // The kappa of the last evaluation was not applied.
+        parallel::for_each_mut(&mut pressure, |i, pressure| {
+            let applied = total_kappa[i] - kappa_over_density[i] * density[i];
+            *pressure = applied * density[i];
+        });
+        self.particles.restore(PRESSURE, pressure);
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::{PressureSolver, SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_dfsph_converges() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 4e-3,
+            gravity: TV::ith(1, -9.81),
+            pressure_solver: PressureSolver::Dfsph {
+                tolerance: 0.001,
+                divergence_tolerance: 0.01,
+                max_iterations: 100,
+            },
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        for _ in 0..20 {
+            sim.advance_timestep();
+            let stats = sim.solver_stats.unwrap();
+            assert!(stats.iterations < 100);
+            assert!(stats.density_error <= 0.001);
+            assert!(sim.particles.alpha().iter().all(|&a| a >= 0.));
+        }
+    }
+}
@@ -0,0 +1,472 @@
+//! Emitters and sinks, which let particles enter and leave the scene during a simulation.
+//!
+//! An [`Emitter`] places new particles at the points of a lattice covering its shape, cycling
+//! through the points at a given rate and skipping points which are still occupied. A [`Sink`]
+//! removes every particle which enters its region, described by a signed distance field. Together,
+//! they allow open scenes such as fountains, pipes and rivers.
+//!
+//! Emitting and removing particles changes their indices, so any per-particle data kept outside of
+//! the simulation should be stored in [`super::SphParticles::data_mut`] instead.
+
+use super::builder::lattice;
+use super::multiphase::PHASE;
+use super::obstacle::Sdf;
+use super::thermal::TEMPERATURE;
+use super::SphSimulation;
+use crate::base::Range;
+use crate::math::*;
+use tracing::instrument;
+
+/// The region in which an emitter creates particles.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmitterShape {
+    /// A box filled with particles.
+    Box(Range<TV>),
+    /// A disk (in 3d) or line segment (in 2d) perpendicular to the emitter velocity, which
+    /// creates one layer of particles at a time.
+    Nozzle { center: TV, radius: T },
+}
+
+/// Creates particles with a given velocity at a steady rate.
+#[derive(Clone, Debug)]
+pub struct Emitter {
+    pub shape: EmitterShape,
+    /// The velocity of the new particles
+    pub velocity: TV,
+    /// The number of particles created per unit time
+    pub rate: T,
+    /// The maximum random offset of new particles along each axis, as a fraction of the spacing
+    pub jitter: T,
+    /// The phase of the new particles, in a multiphase simulation. See
+    /// [`super::SphParamaters::phases`].
+    pub phase: usize,
+    /// The temperature of the new particles with heat transport, or `None` for the reference
+    /// temperature.
+    pub temperature: Option<T>,
+    /// The distance between the particles of the lattice
+    spacing: T,
+    /// The lattice points at which particles are created.
+    samples: Vec<TV>,
+    /// The next lattice point to use.
+    next: usize,
+    /// The fraction of a particle which is carried over to the next step.
+    pending: T,
+    /// The state of the random number generator used for jitter.
+    seed: u64,
+    emitted: usize,
+}
+
+impl Emitter {
+    /// Creates an emitter which fills a box with particles on a lattice with the given spacing.
+    pub fn cuboid(region: Range<TV>, spacing: T, velocity: TV, rate: T) -> Self {
+        let samples = lattice(region, spacing).collect();
+        Self::from_samples(EmitterShape::Box(region), samples, spacing, velocity, rate)
+    }
+
+    /// Creates a nozzle, which emits particles from a disk (in 3d) or line segment (in 2d)
+    /// perpendicular to `velocity`. The rate is chosen so that consecutive layers of particles are
+    /// one spacing apart, which makes a continuous stream.
+    pub fn nozzle(center: TV, radius: T, spacing: T, velocity: TV) -> Self {
+        let direction = velocity
+            .try_normalize(T::EPSILON)
+            .unwrap_or_else(|| TV::ith(DIM - 1, 1.));
+        let tangents = tangent_basis(direction);
+
+        // A single layer of the lattice in the coordinates of the tangents, with the last
+        // coordinate along `direction`.
+        let mut half_size = TV::from_element(radius);
+        half_size[DIM - 1] = 0.5 * spacing;
+        let samples: Vec<TV> = lattice(Range::new(-half_size, half_size), spacing)
+            .filter(|local| local.rows(0, DIM - 1).norm() <= radius)
+            .map(|local| center + (0..DIM - 1).map(|a| local[a] * tangents[a]).sum::<TV>())
+            .collect();
+
+        let rate = samples.len() as T * velocity.norm() / spacing;
+        let shape = EmitterShape::Nozzle { center, radius };
+        Self::from_samples(shape, samples, spacing, velocity, rate)
+    }
+
+    fn from_samples(
+        shape: EmitterShape,
+        samples: Vec<TV>,
+        spacing: T,
+        velocity: TV,
+        rate: T,
+    ) -> Self {
+        Self {
+            shape,
+            velocity,
+            rate,
+            jitter: 0.,
+            phase: 0,
+            temperature: None,
+            spacing,
+            samples,
+            next: 0,
+            pending: 0.,
+            seed: 0x853c_49e6_748f_ea9b,
+            emitted: 0,
+        }
+    }
+
+    /// Sets the number of particles created per unit time.
+    pub fn rate(mut self, rate: T) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Sets the maximum random offset of new particles, as a fraction of the spacing.
+    pub fn jitter(mut self, jitter: T) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the phase of the new particles, in a multiphase simulation.
+    pub fn phase(mut self, phase: usize) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    /// Sets the temperature of the new particles, with heat transport.
+    pub fn temperature(mut self, temperature: T) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the seed of the random number generator used for jitter.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The distance between the particles of the lattice.
+    pub fn spacing(&self) -> T {
+        self.spacing
+    }
+
+    /// The number of lattice points at which particles are created.
+    pub fn num_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// The total number of particles created so far.
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
+
+    /// A uniformly distributed random number in `[-1, 1)`, from a linear congruential generator.
+    fn random(&mut self) -> T {
+        self.seed = self
+            .seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let bits = (self.seed >> 11) as T / (1u64 << 53) as T;
+        2. * bits - 1.
+    }
+
+    /// Returns the positions of the particles created over a time step of length `dt`, given the
+    /// positions of the existing particles.
+    ///
+    /// Lattice points with a particle closer than half of the spacing are skipped, since particles
+    /// created at the same position would never separate. If every point is occupied, fewer
+    /// particles are created.
+    fn emit(&mut self, dt: T, position: &[TV]) -> Vec<TV> {
+        if self.samples.is_empty() {
+            return Vec::new();
+        }
+
+        self.pending += self.rate.max(0.) * dt;
+        let count = self.pending.floor();
+        self.pending -= count;
+        if count == 0. {
+            return Vec::new();
+        }
+
+        let min_distance = 0.5 * self.spacing;
+        let bounds = self
+            .samples
+            .iter()
+            .fold(
+                Range::new(self.samples[0], self.samples[0]),
+                |bounds, &x| Range::new(bounds.min.inf(&x), bounds.max.sup(&x)),
+            )
+            .thickened(min_distance);
+        let nearby: Vec<TV> = position
+            .iter()
+            .copied()
+            .filter(|&x| bounds.contains(x))
+            .collect();
+        let mut occupied: Vec<bool> = self
+            .samples
+            .iter()
+            .map(|sample| nearby.iter().any(|x| (x - sample).norm() < min_distance))
+            .collect();
+
+        let mut created = Vec::new();
+        for _ in 0..count as usize {
+            let Some(offset) = (0..self.samples.len())
+                .find(|offset| !occupied[(self.next + offset) % self.samples.len()])
+            else {
+                break;
+            };
+            let index = (self.next + offset) % self.samples.len();
+            occupied[index] = true;
+            self.next = (index + 1) % self.samples.len();
+            self.emitted += 1;
+
+            let scale = self.jitter * self.spacing;
+            created.push(self.samples[index] + TV::from_fn(|_, _| scale * self.random()));
+        }
+        created
+    }
+}
+
+/// Returns `DIM - 1` orthonormal vectors perpendicular to the unit vector `direction`.
+fn tangent_basis(direction: TV) -> Vec<TV> {
+    let mut tangents: Vec<TV> = Vec::with_capacity(DIM - 1);
+    for a in 0..DIM {
+        let mut t = TV::ith(a, 1.) - direction[a] * direction;
+        for u in &tangents {
+            t -= t.dot(u) * u;
+        }
+        if tangents.len() < DIM - 1 && t.norm() > 0.5 {
+            tangents.push(t.normalize());
+        }
+    }
+    tangents
+}
+
+/// Removes the particles which enter a region.
+pub struct Sink {
+    /// The particles where this is negative are removed.
+    pub sdf: Box<dyn Sdf>,
+    removed: usize,
+}
+
+impl Sink {
+    pub fn new<S: Sdf + 'static>(sdf: S) -> Self {
+        Self {
+            sdf: Box::new(sdf),
+            removed: 0,
+        }
+    }
+
+    /// The total number of particles removed so far.
+    pub fn removed(&self) -> usize {
+        self.removed
+    }
+}
+
+impl SphSimulation {
+    /// Removes the particles inside of sinks, and creates new particles at the emitters. The
+    /// particles created by each emitter have the mass `rest_density * spacing^DIM`, using the
+    /// rest density of the emitter's phase in multiphase simulations.
+    #[instrument(skip_all)]
+    pub(super) fn apply_emitters_and_sinks(&mut self) {
+        if self.emitters.is_empty() && self.sinks.is_empty() {
+            return;
+        }
+        let n = self.params.num_particles;
+
+        let position = self.particles.position();
+        let mut removed = vec![false; n];
+        for sink in &mut self.sinks {
+            for i in 0..n {
+                if !removed[i] && sink.sdf.distance(position[i]) < 0. {
+                    removed[i] = true;
+                    sink.removed += 1;
+                }
+            }
+        }
+        self.particles.retain(|i| !removed[i]);
+
+        let dt = self.params.delta_time;
+        let multiphase = self.is_multiphase();
+        for emitter in &mut self.emitters {
+            let mass =
+                self.params.phase_rest_density(emitter.phase) * emitter.spacing.powi(DIM as i32);
+            let temperature = self
+                .params
+                .thermal
+                .as_ref()
+                .map(|thermal| emitter.temperature.unwrap_or(thermal.reference_temperature));
+            for x in emitter.emit(dt, self.particles.position()) {
+                self.particles.push(mass, x, emitter.velocity);
+                let data = self.particles.data_mut();
+                if multiphase {
+                    *data.get_mut(PHASE).unwrap().last_mut().unwrap() = emitter.phase;
+                }
+                if let Some(temperature) = temperature {
+                    *data.get_mut(TEMPERATURE).unwrap().last_mut().unwrap() = temperature;
+                }
+            }
+        }
+
+        self.params.num_particles = self.particles.len();
+        if self.params.num_particles != n || removed.contains(&true) {
+            self.invalidate_neighbor_lists();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::obstacle::HalfSpace;
+    use crate::sph::{
+        EquationOfState, Phase, PressureSolver, SphParamaters, SphSimulationBuilder,
+        SphSimulationError, ThermalParameters,
+    };
+
+    #[test]
+    fn test_nozzle_samples() {
+        let velocity = TV::from_element(1.);
+        let emitter = Emitter::nozzle(TV::from_element(1.), 0.1, 0.02, velocity);
+        let direction = velocity.normalize();
+        assert!(emitter.num_samples() > 0);
+        for &x in &emitter.samples {
+            let offset = x - TV::from_element(1.);
+            assert!(offset.dot(&direction).abs() < 1e-12);
+            assert!(offset.norm() <= 0.1 + 1e-12);
+        }
+        let expected = emitter.num_samples() as T * velocity.norm() / 0.02;
+        assert!((emitter.rate - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_still_box_emitter() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.2)),
+            gravity: TV::zeros(),
+            ..Default::default()
+        };
+        let spacing = 0.5 * params.h;
+        let region = Range::new(TV::from_element(0.05), TV::from_element(0.15));
+        // Without a velocity, the particles stay where they are created.
+        let emitter = Emitter::cuboid(region, spacing, TV::zeros(), 1e4);
+        let num_samples = emitter.num_samples();
+        let mut sim = SphSimulationBuilder::new(params)
+            .emitter(emitter)
+            .build()
+            .unwrap();
+
+        for _ in 0..10 {
+            sim.advance_timestep();
+        }
+
+        let position = sim.particles.position();
+        assert!(position.len() >= num_samples);
+        for i in 0..position.len() {
+            for j in 0..i {
+                assert!((position[i] - position[j]).norm() > 0.1 * spacing);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipe_flow() {
+        let params = SphParamaters {
+            domain: Range::new(
+                TV::zeros(),
+                TV::from_fn(|a, _| if a == 0 { 0.4 } else { 0.2 }),
+            ),
+            delta_time: 2e-3,
+            gravity: TV::zeros(),
+            ..Default::default()
+        };
+        let spacing = 0.5 * params.h;
+        let velocity = TV::ith(0, 2.);
+        let center = TV::from_fn(|a, _| if a == 0 { 0.05 } else { 0.1 });
+        let emitter = Emitter::nozzle(center, 0.03, spacing, velocity).jitter(0.05);
+        let sink = Sink::new(HalfSpace {
+            point: TV::ith(0, 0.3),
+            normal: TV::ith(0, -1.),
+        });
+        let mut sim = SphSimulationBuilder::new(params)
+            .emitter(emitter)
+            .sink(sink)
+            .build()
+            .unwrap();
+        assert_eq!(sim.params.num_particles, 0);
+
+        for _ in 0..90 {
+            sim.advance_timestep();
+            assert_eq!(sim.particles.len(), sim.params.num_particles);
+        }
+
+        let emitted = sim.emitters[0].emitted();
+        let removed = sim.sinks[0].removed();
+        assert!(removed > 0);
+        assert_eq!(emitted - removed, sim.params.num_particles);
+        // Particles are removed at the start of the step after they enter the sink.
+        let max_x = 0.3 + 2. * velocity.norm() * sim.params.delta_time;
+        assert!(sim.particles.position().iter().all(|x| x[0] < max_x));
+    }
+
+    #[test]
+    fn test_emit_second_phase() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.2)),
+            gravity: TV::zeros(),
+            pressure_solver: PressureSolver::StateEquation,
+            equation_of_state: EquationOfState::tait(10.),
+            phases: vec![Phase::new(1000., 1.), Phase::new(500., 1.)],
+            ..Default::default()
+        };
+        let spacing = 0.5 * params.h;
+        let region = Range::new(TV::from_element(0.1), TV::from_element(0.15));
+        let emitter = Emitter::cuboid(region, spacing, TV::zeros(), 1e4).phase(1);
+        let mut sim = SphSimulationBuilder::new(params.clone())
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.05)))
+            .emitter(emitter.clone())
+            .build()
+            .unwrap();
+        let initial = sim.params.num_particles;
+
+        sim.advance_timestep();
+        let n = sim.params.num_particles;
+        assert!(n > initial);
+        let phases = sim.particle_phases().unwrap();
+        assert!(phases[..initial].iter().all(|&phase| phase == 0));
+        assert!(phases[initial..].iter().all(|&phase| phase == 1));
+        let mass = 500. * spacing.powi(DIM as i32);
+        assert!(sim.particles.mass()[initial..].iter().all(|&m| m == mass));
+
+        let invalid = SphSimulationBuilder::new(params)
+            .emitter(emitter.phase(2))
+            .build();
+        assert!(matches!(
+            invalid,
+            Err(SphSimulationError::InvalidPhase { phase: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_emit_hot_fluid() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.2)),
+            gravity: TV::zeros(),
+            thermal: Some(ThermalParameters {
+                reference_temperature: 20.,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let spacing = 0.5 * params.h;
+        let region = Range::new(TV::from_element(0.1), TV::from_element(0.15));
+        let emitter = Emitter::cuboid(region, spacing, TV::zeros(), 1e4).temperature(80.);
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.05)))
+            .emitter(emitter)
+            .build()
+            .unwrap();
+        let initial = sim.params.num_particles;
+
+        sim.advance_timestep();
+        let temperature = sim.particles.data().get(TEMPERATURE).unwrap();
+        assert!(temperature.len() > initial);
+        // The temperatures change slightly by diffusion during the step.
+        assert!(temperature[..initial].iter().all(|&t| t < 25.));
+        assert!(temperature[initial..].iter().all(|&t| t > 75.));
+    }
+}
@@ -0,0 +1,201 @@
+//! Implicit incompressible SPH (IISPH), from
+//!
+//! * Ihmsen, M., Cornelis, J., Solenthaler, B., Horvath, C., & Teschner, M. (2014). Implicit incompressible SPH. IEEE Transactions on Visualization and Computer Graphics, 20(3), 426-435.
+//!
+//! IISPH discretizes the pressure Poisson equation `dt^2 * laplacian(p) = rho_0 - rho_adv` using
+//! the SPH pressure force, where `rho_adv` is the density after applying the non-pressure forces.
+//! The resulting linear system is solved with relaxed Jacobi iterations. Only the diagonal of the
+//! system is stored; the off-diagonal terms are evaluated on the fly as sums over the neighbors.
+
+use super::particles::{FORCE, PRESSURE};
+use super::{SolverStats, SphSimulation};
+use crate::math::*;
+use crate::util::parallel;
+use tracing::{instrument, trace};
+
+/// The minimum number of iterations, since the first iterations are not yet accurate enough to
+/// judge convergence.
+const MIN_ITERATIONS: usize = 2;
+
+impl SphSimulation {
+    /// Computes pressures with IISPH, and adds the resulting pressure forces to the particles.
+    ///
+    /// The non-pressure forces must already have been applied. The pressures from the previous
+    /// time step are used as an initial guess.
+    #[instrument(skip_all)]
+    pub(super) fn iisph_solve(
+        &mut self,
+        tolerance: T,
+        max_iterations: usize,
+        omega: T,
+    ) -> SolverStats {
+        let n = self.params.num_particles;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let dt = self.params.delta_time;
+        let rest_density = self.params.rest_density;
+        let kernel = self.params.pressure_kernel;
+        let deterministic = self.params.deterministic;
+
+        if n == 0 {
+            return SolverStats::default();
+        }
+
+        let neighbors = self.neighbor_lists();
+        let boundary_gradients = self.boundary_gradients();
+        let mut pressure = self.particles.take(PRESSURE);
+        let mut force = self.particles.take(FORCE);
+        let particles = &self.particles;
+        let mass = particles.mass();
+        let density = particles.density();
+        let position = particles.position();
+        let velocity = particles.velocity();
+
+        // Kernel gradients for every neighbor pair, since they are used in every iteration.
+        let gradients: Vec<Vec<TV>> = parallel::map(n, |i| {
+            neighbors[i]
+                .iter()
+                .map(|&j| kernel.gradient(periodic.displacement(position[i], position[j]), h))
+                .collect()
+        });
+
+        let velocity_adv = parallel::map(n, |i| velocity[i] + dt * force[i] / density[i]);
+
+        // The displacement of particle i due to its own pressure is `d_ii * p_i`.
+        let d_ii = parallel::map(n, |i| {
+            let sum = neighbors[i]
+                .iter()
+                .zip(&gradients[i])
+                .map(|(&j, &grad)| mass[j] * grad)
+                .sum::<TV>()
+                + boundary_gradients[i];
+            -dt * dt / (density[i] * density[i]) * sum
+        });
+
+        // The advected density of each particle, and the diagonal `a_ii` of the system.
+        let (density_adv, a_ii): (Vec<T>, Vec<T>) = parallel::map(n, |i| {
+            let d_ji_coeff = dt * dt * mass[i] / (density[i] * density[i]);
+            let mut divergence = 0.;
+            let mut diagonal = 0.;
+            for (&j, &grad) in neighbors[i].iter().zip(&gradients[i]) {
+                divergence += mass[j] * (velocity_adv[i] - velocity_adv[j]).dot(&grad);
+                // d_ji = -dt^2 m_i / rho_i^2 grad W_ji = dt^2 m_i / rho_i^2 grad W_ij
+                let d_ji = d_ji_coeff * grad;
+                diagonal += mass[j] * (d_ii[i] - d_ji).dot(&grad);
+            }
+            // The boundary is static, and has no pressure of its own.
+            divergence += velocity_adv[i].dot(&boundary_gradients[i]);
+            diagonal += d_ii[i].dot(&boundary_gradients[i]);
+            (density[i] + dt * divergence, diagonal)
+        })
+        .into_iter()
+        .unzip();
+
+        for p in pressure.iter_mut() {
+            *p *= 0.5;
+        }
+
+        let mut sum_d_ij_p_j = vec![TV::zeros(); n];
+        let mut stats = SolverStats::default();
+        while stats.iterations < max_iterations {
+            parallel::for_each_mut(&mut sum_d_ij_p_j, |i, sum| {
+                *sum = neighbors[i]
+                    .iter()
+                    .zip(&gradients[i])
+                    .map(|(&j, &grad)| {
+                        -dt * dt * mass[j] / (density[j] * density[j]) * pressure[j] * grad
+                    })
+                    .sum();
+            });
+
+            // The relaxed Jacobi update of each pressure, and the predicted density error with the
+            // current pressures.
+            let (new_pressure, errors): (Vec<T>, Vec<T>) = parallel::map(n, |i| {
+                let d_ji_coeff = dt * dt * mass[i] / (density[i] * density[i]);
+                let off_diagonal: T = neighbors[i]
+                    .iter()
+                    .zip(&gradients[i])
+                    .map(|(&j, &grad)| {
+                        let d_ji = d_ji_coeff * grad;
+                        let d_jj_p_j = d_ii[j] * pressure[j];
+                        let sum_d_jk_p_k = sum_d_ij_p_j[j] - d_ji * pressure[i];
+                        mass[j] * (sum_d_ij_p_j[i] - d_jj_p_j - sum_d_jk_p_k).dot(&grad)
+                    })
+                    .sum::<T>()
+                    + sum_d_ij_p_j[i].dot(&boundary_gradients[i]);
+
+                let rhs = rest_density - density_adv[i] - off_diagonal;
+                let new_pressure = if a_ii[i].abs() > T::EPSILON {
+                    ((1. - omega) * pressure[i] + omega * rhs / a_ii[i]).max(0.)
+                } else {
+                    0.
+                };
+
+                // Only compression is counted, since particles at the free surface have
+                // incomplete neighborhoods.
+                let predicted = density_adv[i] + a_ii[i] * pressure[i] + off_diagonal;
+                (new_pressure, (predicted - rest_density).max(0.))
+            })
+            .into_iter()
+            .unzip();
+            pressure.copy_from_slice(&new_pressure);
+            let total_error = parallel::sum(&errors, deterministic);
+
+            stats.iterations += 1;
+            stats.density_error = total_error / (n.max(1) as T * rest_density);
+            trace!(stats.iterations, stats.density_error);
+
+            if stats.iterations >= MIN_ITERATIONS && stats.density_error <= tolerance {
+                break;
+            }
+        }
+
+        parallel::for_each_mut(&mut force, |i, force| {
+            let p_i = pressure[i] / (density[i] * density[i]);
+            let acceleration = -neighbors[i]
+                .iter()
+                .zip(&gradients[i])
+                .map(|(&j, &grad)| mass[j] * (p_i + pressure[j] / (density[j] * density[j])) * grad)
+                .sum::<TV>();
+            *force += density[i] * acceleration;
+        });
+        self.particles.restore(PRESSURE, pressure);
+        self.particles.restore(FORCE, force);
+        self.apply_boundary_pressure_force();
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::{PressureSolver, SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_iisph_converges() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 4e-3,
+            gravity: TV::ith(1, -9.81),
+            pressure_solver: PressureSolver::Iisph {
+                tolerance: 0.01,
+                max_iterations: 100,
+                omega: 0.5,
+            },
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        for _ in 0..20 {
+            sim.advance_timestep();
+            let stats = sim.solver_stats.unwrap();
+            assert!(stats.iterations < 100);
+            assert!(stats.density_error <= 0.01);
+        }
+    }
+}
@@ -1,32 +1,103 @@
 //! Contains implementations of several commonly used smoothing kernels in SPH.
+//!
+//! Every kernel is normalized so that it integrates to one over its support, which is a circle (in
+//! 2d) or sphere (in 3d) of radius `h`. The normalization constants depend on the dimension, so
+//! they are selected using [`DIM`].
+//!
+//! The kernels used by [`super::SphSimulation`] are chosen at runtime using [`KernelType`].
 
 use crate::math::*;
+use std::f64::consts::PI;
 
 pub trait SmoothingKernel {
-    fn value(_r: TV, _h: T) -> T {
-        0.
+    /// The value of the kernel at the offset `r`.
+    fn value(r: TV, h: T) -> T;
+
+    /// The derivative of the kernel with respect to `|r|`.
+    fn gradient_mag(r: TV, h: T) -> T;
+
+    /// The gradient of the kernel with respect to `r`.
+    fn gradient(r: TV, h: T) -> TV {
+        match r.try_normalize(0.) {
+            Some(dir) => dir * Self::gradient_mag(r, h),
+            None => TV::zeros(),
+        }
     }
 
-    fn gradient_mag(_r: TV, _h: T) -> T {
-        0.
+    /// The Laplacian of the kernel with respect to `r`.
+    fn laplacian(r: TV, h: T) -> T;
+}
+
+/// The Laplacian of a radially symmetric function, given its first and second derivatives with
+/// respect to `r`.
+///
+/// At `r = 0`, the first derivative of a smooth kernel vanishes, and the Laplacian is `DIM` times
+/// the second derivative.
+fn radial_laplacian(r: T, d1: T, d2: T) -> T {
+    if r > 0. {
+        d2 + (DIM - 1) as T * d1 / r
+    } else {
+        DIM as T * d2
     }
+}
 
-    fn gradient(r: TV, h: T) -> TV {
-        r.normalize() * Self::gradient_mag(r, h)
+/// A kernel given by `normalization(h) * shape(r / h)`, where the shape function vanishes for
+/// `q = r / h >= 1`.
+trait ShapeFunction {
+    fn normalization(h: T) -> T;
+
+    /// Returns the shape function and its first and second derivatives at `q`.
+    fn shape(q: T) -> (T, T, T);
+}
+
+impl<K: ShapeFunction> SmoothingKernel for K {
+    fn value(r: TV, h: T) -> T {
+        let q = r.magnitude() / h;
+        if q < 1. {
+            K::normalization(h) * K::shape(q).0
+        } else {
+            0.
+        }
+    }
+
+    fn gradient_mag(r: TV, h: T) -> T {
+        let q = r.magnitude() / h;
+        if q < 1. {
+            K::normalization(h) * K::shape(q).1 / h
+        } else {
+            0.
+        }
     }
 
-    fn laplacian(_r: TV, _h: T) -> T {
-        0.
+    fn laplacian(r: TV, h: T) -> T {
+        let mag = r.magnitude();
+        let q = mag / h;
+        if q < 1. {
+            let (_, d1, d2) = K::shape(q);
+            K::normalization(h) * radial_laplacian(mag, d1 / h, d2 / (h * h))
+        } else {
+            0.
+        }
     }
 }
 
 pub struct SpikyKernel;
 
+impl SpikyKernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 10. / (PI * h.powi(5)),
+            3 => 15. / (PI * h.powi(6)),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl SmoothingKernel for SpikyKernel {
     fn value(r: TV, h: T) -> T {
         let r_mag = r.magnitude();
         if r_mag >= 0. && r_mag <= h {
-            let c = 15. / (std::f64::consts::PI * h.powi(6));
+            let c = Self::normalization(h);
             let h_sub_r = h - r_mag;
             c * h_sub_r * h_sub_r * h_sub_r
         } else {
@@ -37,20 +108,41 @@ impl SmoothingKernel for SpikyKernel {
     fn gradient_mag(r: TV, h: T) -> T {
         let r_mag = r.magnitude();
         if r_mag >= 0. && r_mag <= h {
-            let c = 15. * -3. / (std::f64::consts::PI * h.powi(6));
+            let c = -3. * Self::normalization(h);
             let h_sub_r = h - r_mag;
             c * h_sub_r * h_sub_r
         } else {
             0.
         }
     }
+
+    fn laplacian(r: TV, h: T) -> T {
+        let r_mag = r.magnitude();
+        if r_mag <= h {
+            let c = Self::normalization(h);
+            let h_sub_r = h - r_mag;
+            radial_laplacian(r_mag, -3. * c * h_sub_r * h_sub_r, 6. * c * h_sub_r)
+        } else {
+            0.
+        }
+    }
 }
 
 pub struct Poly6Kernel;
 
+impl Poly6Kernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 4. / (PI * h.powi(8)),
+            3 => 315. / (64. * PI * h.powi(9)),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl SmoothingKernel for Poly6Kernel {
     fn value(r: TV, h: T) -> T {
-        let c = 315. / (64. * std::f64::consts::PI * h.powi(9));
+        let c = Self::normalization(h);
         let mag2 = r.magnitude_squared();
 
         if mag2 <= h * h && mag2 >= 0. {
@@ -61,7 +153,7 @@ impl SmoothingKernel for Poly6Kernel {
     }
 
     fn gradient_mag(r: TV, h: T) -> T {
-        let c = 315. / (64. * std::f64::consts::PI * h.powi(9));
+        let c = Self::normalization(h);
         let mag2 = r.magnitude_squared();
         if mag2 <= h * h && mag2 > 0. {
             c * 3. * -2. * mag2.sqrt() * (h * h - mag2) * (h * h - mag2)
@@ -69,13 +161,79 @@ impl SmoothingKernel for Poly6Kernel {
             0.
         }
     }
+
+    fn laplacian(r: TV, h: T) -> T {
+        let c = Self::normalization(h);
+        let mag2 = r.magnitude_squared();
+        if mag2 <= h * h {
+            let diff = h * h - mag2;
+            c * diff * (24. * mag2 - 6. * DIM as T * diff)
+        } else {
+            0.
+        }
+    }
 }
 
+/// The viscosity kernel from [Müller et al. 2003], whose Laplacian is `c * (h - r)`.
+///
+/// In 3d, this is the kernel from the paper. In 2d, the same construction (a kernel whose Laplacian
+/// is linear in `r`, with the value and gradient vanishing at `h`) leads to a logarithmic term in
+/// place of the `h / 2r` term.
 pub struct ViscosityKernel;
 
+impl ViscosityKernel {
+    fn laplacian_normalization(h: T) -> T {
+        match DIM {
+            2 => 40. / (PI * h.powi(5)),
+            3 => 45. / (PI * h.powi(6)),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl SmoothingKernel for ViscosityKernel {
+    fn value(r: TV, h: T) -> T {
+        let mag = r.magnitude();
+        if mag > h {
+            return 0.;
+        }
+
+        let q = mag / h;
+        match DIM {
+            2 => {
+                let c = 40. / (PI * h * h);
+                c * (-q.powi(3) / 9. + q * q / 4. - q.ln() / 6. - 5. / 36.)
+            }
+            3 => {
+                let c = 15. / (2. * PI * h.powi(3));
+                c * (-0.5 * q.powi(3) + q * q + 0.5 / q - 1.)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn gradient_mag(r: TV, h: T) -> T {
+        let mag = r.magnitude();
+        if mag > h {
+            return 0.;
+        }
+
+        let q = mag / h;
+        match DIM {
+            2 => {
+                let c = 40. / (PI * h.powi(3));
+                c * (-q * q / 3. + 0.5 * q - 1. / (6. * q))
+            }
+            3 => {
+                let c = 15. / (2. * PI * h.powi(4));
+                c * (-1.5 * q * q + 2. * q - 0.5 / (q * q))
+            }
+            _ => unreachable!(),
+        }
+    }
+
     fn laplacian(r: TV, h: T) -> T {
-        let c = 45. / (std::f64::consts::PI * h.powi(6));
+        let c = Self::laplacian_normalization(h);
 
         let mag = r.magnitude();
         if mag <= h {
@@ -85,3 +243,268 @@ impl SmoothingKernel for ViscosityKernel {
         }
     }
 }
+
+/// The cubic spline kernel from [Monaghan 1992], scaled to have support `h`.
+pub struct CubicSplineKernel;
+
+impl ShapeFunction for CubicSplineKernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 40. / (7. * PI * h * h),
+            3 => 8. / (PI * h.powi(3)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn shape(q: T) -> (T, T, T) {
+        if q <= 0.5 {
+            (
+                6. * (q * q * q - q * q) + 1.,
+                18. * q * q - 12. * q,
+                36. * q - 12.,
+            )
+        } else {
+            let a = 1. - q;
+            (2. * a * a * a, -6. * a * a, 12. * a)
+        }
+    }
+}
+
+/// The quintic spline kernel from [Morris et al. 1997], scaled to have support `h`.
+pub struct QuinticSplineKernel;
+
+impl ShapeFunction for QuinticSplineKernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 63. / (478. * PI * h * h),
+            3 => 9. / (40. * PI * h.powi(3)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn shape(q: T) -> (T, T, T) {
+        // The spline is usually written in terms of s = 3q, which has support [0, 3].
+        let s = 3. * q;
+        let mut result = (0., 0., 0.);
+        for (offset, coeff) in [(3., 1.), (2., -6.), (1., 15.)] {
+            let a: T = offset - s;
+            if a > 0. {
+                result.0 += coeff * a.powi(5);
+                result.1 += coeff * -15. * a.powi(4);
+                result.2 += coeff * 180. * a.powi(3);
+            }
+        }
+        result
+    }
+}
+
+/// The Wendland C2 kernel, with the 2d and 3d normalizations given by [Dehnen and Aly 2012].
+pub struct WendlandC2Kernel;
+
+impl ShapeFunction for WendlandC2Kernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 7. / (PI * h * h),
+            3 => 21. / (2. * PI * h.powi(3)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn shape(q: T) -> (T, T, T) {
+        let a = 1. - q;
+        (
+            a.powi(4) * (1. + 4. * q),
+            -20. * q * a.powi(3),
+            20. * a * a * (4. * q - 1.),
+        )
+    }
+}
+
+/// The Wendland C4 kernel, with the 2d and 3d normalizations given by [Dehnen and Aly 2012].
+pub struct WendlandC4Kernel;
+
+impl ShapeFunction for WendlandC4Kernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 9. / (PI * h * h),
+            3 => 495. / (32. * PI * h.powi(3)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn shape(q: T) -> (T, T, T) {
+        let a = 1. - q;
+        (
+            a.powi(6) * (1. + 6. * q + 35. / 3. * q * q),
+            -56. / 3. * q * a.powi(5) * (1. + 5. * q),
+            -56. / 3. * a.powi(4) * (1. + 4. * q - 35. * q * q),
+        )
+    }
+}
+
+/// The Wendland C6 kernel, with the 2d and 3d normalizations given by [Dehnen and Aly 2012].
+pub struct WendlandC6Kernel;
+
+impl ShapeFunction for WendlandC6Kernel {
+    fn normalization(h: T) -> T {
+        match DIM {
+            2 => 78. / (7. * PI * h * h),
+            3 => 1365. / (64. * PI * h.powi(3)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn shape(q: T) -> (T, T, T) {
+        let a = 1. - q;
+        (
+            a.powi(8) * (1. + 8. * q + 25. * q * q + 32. * q.powi(3)),
+            -22. * q * a.powi(7) * (1. + 7. * q + 16. * q * q),
+            -22. * a.powi(6) * (1. + 6. * q - 15. * q * q - 160. * q.powi(3)),
+        )
+    }
+}
+
+/// Selects a smoothing kernel at runtime, so it can be set in [`super::SphParamaters`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum KernelType {
+    Poly6,
+    Spiky,
+    Viscosity,
+    CubicSpline,
+    QuinticSpline,
+    WendlandC2,
+    WendlandC4,
+    WendlandC6,
+}
+
+/// Calls `$method` on the kernel corresponding to a `KernelType`.
+macro_rules! dispatch {
+    ($kernel:expr, $method:ident($($arg:expr),*)) => {
+        match $kernel {
+            KernelType::Poly6 => Poly6Kernel::$method($($arg),*),
+            KernelType::Spiky => SpikyKernel::$method($($arg),*),
+            KernelType::Viscosity => ViscosityKernel::$method($($arg),*),
+            KernelType::CubicSpline => CubicSplineKernel::$method($($arg),*),
+            KernelType::QuinticSpline => QuinticSplineKernel::$method($($arg),*),
+            KernelType::WendlandC2 => WendlandC2Kernel::$method($($arg),*),
+            KernelType::WendlandC4 => WendlandC4Kernel::$method($($arg),*),
+            KernelType::WendlandC6 => WendlandC6Kernel::$method($($arg),*),
+        }
+    };
+}
+
+impl KernelType {
+    pub fn value(self, r: TV, h: T) -> T {
+        dispatch!(self, value(r, h))
+    }
+
+    pub fn gradient_mag(self, r: TV, h: T) -> T {
+        dispatch!(self, gradient_mag(r, h))
+    }
+
+    pub fn gradient(self, r: TV, h: T) -> TV {
+        dispatch!(self, gradient(r, h))
+    }
+
+    pub fn laplacian(self, r: TV, h: T) -> T {
+        dispatch!(self, laplacian(r, h))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Integrates a radially symmetric kernel over its support, using the midpoint rule in the
+    /// radial direction.
+    fn integrate<K: SmoothingKernel>(h: T) -> T {
+        let surface_area = match DIM {
+            2 => 2. * PI,
+            3 => 4. * PI,
+            _ => unreachable!(),
+        };
+
+        let n = 100_000;
+        let dr = h / n as T;
+        (0..n)
+            .map(|i| {
+                let r = (i as T + 0.5) * dr;
+                surface_area * r.powi(DIM as i32 - 1) * K::value(TV::ith(0, r), h) * dr
+            })
+            .sum()
+    }
+
+    fn assert_normalized<K: SmoothingKernel>() {
+        for h in [0.04, 0.5, 1.] {
+            let integral = integrate::<K>(h);
+            assert!(
+                (integral - 1.).abs() < 1e-4,
+                "kernel integrates to {integral} with h = {h}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_poly6_normalized() {
+        assert_normalized::<Poly6Kernel>();
+    }
+
+    #[test]
+    fn test_spiky_normalized() {
+        assert_normalized::<SpikyKernel>();
+    }
+
+    #[test]
+    fn test_viscosity_normalized() {
+        assert_normalized::<ViscosityKernel>();
+    }
+
+    #[test]
+    fn test_splines_normalized() {
+        assert_normalized::<CubicSplineKernel>();
+        assert_normalized::<QuinticSplineKernel>();
+    }
+
+    #[test]
+    fn test_wendland_normalized() {
+        assert_normalized::<WendlandC2Kernel>();
+        assert_normalized::<WendlandC4Kernel>();
+        assert_normalized::<WendlandC6Kernel>();
+    }
+
+    /// Checks the gradient and Laplacian of a kernel against central differences of its value.
+    fn assert_derivatives<K: SmoothingKernel>() {
+        let h = 0.5;
+        let eps = 1e-5;
+        for i in 1..20 {
+            let r = TV::from_element(h / 20. * i as T / (DIM as T).sqrt());
+
+            let mut gradient = TV::zeros();
+            let mut laplacian = 0.;
+            for a in 0..DIM {
+                let dr = TV::ith(a, eps);
+                let (plus, minus) = (K::value(r + dr, h), K::value(r - dr, h));
+                gradient[a] = (plus - minus) / (2. * eps);
+                laplacian += (plus - 2. * K::value(r, h) + minus) / (eps * eps);
+            }
+
+            let scale = K::value(TV::ith(0, 0.5 * h), h) / h;
+            let gradient_err = (K::gradient(r, h) - gradient).norm();
+            assert!(gradient_err < 1e-4 * (gradient.norm() + scale));
+            let laplacian_err = (K::laplacian(r, h) - laplacian).abs();
+            assert!(laplacian_err < 1e-3 * (laplacian.abs() + scale / h));
+        }
+    }
+
+    #[test]
+    fn test_kernel_derivatives() {
+        assert_derivatives::<Poly6Kernel>();
+        assert_derivatives::<SpikyKernel>();
+        assert_derivatives::<ViscosityKernel>();
+        assert_derivatives::<CubicSplineKernel>();
+        assert_derivatives::<QuinticSplineKernel>();
+        assert_derivatives::<WendlandC2Kernel>();
+        assert_derivatives::<WendlandC4Kernel>();
+        assert_derivatives::<WendlandC6Kernel>();
+    }
+}
@@ -8,10 +8,35 @@
 //! the simulation of fluids, while the latter is a recent tutorial which covers the development of
 //! SPH methods in graphics over the past 20 years.
 
-mod kernels;
+pub mod boundary;
+mod builder;
+mod dfsph;
+pub mod emitter;
+mod iisph;
+pub mod kernels;
+pub mod multiphase;
+pub mod obstacle;
 mod parameters;
-mod particles;
+pub mod particles;
+mod pbf;
+mod pcisph;
+pub mod rheology;
 mod simulation;
+pub mod surface_tension;
+pub mod thermal;
+mod time_step;
+mod viscosity;
 
-pub use parameters::SphParamaters;
-pub use simulation::SphSimulation;
+pub use boundary::SphBoundary;
+pub use builder::SphSimulationBuilder;
+pub use emitter::{Emitter, Sink};
+pub use kernels::KernelType;
+pub use multiphase::Phase;
+pub use obstacle::{Obstacle, Sdf};
+pub use parameters::{EquationOfState, PressureSolver, SphParamaters, ViscosityModel};
+pub use particles::SphParticles;
+pub use pbf::PbfParameters;
+pub use rheology::Rheology;
+pub use simulation::{SolverStats, SphSimulation, SphSimulationError};
+pub use thermal::{HeatSource, ThermalParameters, ThermalViscosity};
+pub use time_step::AdaptiveTimeStep;
@@ -0,0 +1,403 @@
+//! Static obstacles described by signed distance fields (SDFs).
+//!
+//! A signed distance field gives the distance from a point to the surface of the obstacle, which
+//! is negative inside of the obstacle. Particles which end up inside an obstacle are projected back
+//! to the surface along the gradient of the SDF, and their velocities are corrected using the
+//! restitution and friction coefficients of the obstacle.
+
+use super::particles::{POSITION, VELOCITY};
+use super::SphSimulation;
+use crate::base::{ArrayNd, Grid, Range, RangeIterator, VecExtPartialOrd};
+use crate::math::*;
+use tracing::instrument;
+
+/// The step used to approximate gradients with central differences.
+const GRADIENT_EPSILON: T = 1e-6;
+
+/// A signed distance field, which is negative inside of the solid.
+pub trait Sdf: Send + Sync {
+    /// The signed distance from `x` to the surface.
+    fn distance(&self, x: TV) -> T;
+
+    /// The gradient of the distance, which is the outward normal on the surface. This defaults to
+    /// central differences.
+    fn gradient(&self, x: TV) -> TV {
+        TV::from_fn(|i, _| {
+            let dx = TV::ith(i, GRADIENT_EPSILON);
+            (self.distance(x + dx) - self.distance(x - dx)) / (2. * GRADIENT_EPSILON)
+        })
+    }
+
+    /// The union of two solids.
+    fn union<B: Sdf>(self, other: B) -> Union<Self, B>
+    where
+        Self: Sized,
+    {
+        Union(self, other)
+    }
+
+    /// The intersection of two solids.
+    fn intersection<B: Sdf>(self, other: B) -> Intersection<Self, B>
+    where
+        Self: Sized,
+    {
+        Intersection(self, other)
+    }
+
+    /// This solid, with `other` removed from it.
+    fn difference<B: Sdf>(self, other: B) -> Difference<Self, B>
+    where
+        Self: Sized,
+    {
+        Difference(self, other)
+    }
+
+    /// Swaps the inside and outside of the solid. The complement of a box is a container.
+    fn complement(self) -> Complement<Self>
+    where
+        Self: Sized,
+    {
+        Complement(self)
+    }
+}
+
+/// A circle (in 2d) or sphere (in 3d).
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Sphere {
+    pub center: TV,
+    pub radius: T,
+}
+
+impl Sdf for Sphere {
+    fn distance(&self, x: TV) -> T {
+        (x - self.center).norm() - self.radius
+    }
+
+    fn gradient(&self, x: TV) -> TV {
+        (x - self.center)
+            .try_normalize(T::EPSILON)
+            .unwrap_or_else(|| TV::ith(0, 1.))
+    }
+}
+
+/// An axis-aligned box.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Cuboid {
+    pub range: Range<TV>,
+}
+
+impl Sdf for Cuboid {
+    fn distance(&self, x: TV) -> T {
+        let center = 0.5 * (self.range.min + self.range.max);
+        let half_size = 0.5 * self.range.size();
+        let q = (x - center).abs() - half_size;
+        let outside = q.map(|q| q.max(0.)).norm();
+        let inside = q.max().min(0.);
+        outside + inside
+    }
+}
+
+/// The half space `(x - point) . normal < 0`, such as a floor.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HalfSpace {
+    pub point: TV,
+    /// The outward unit normal.
+    pub normal: TV,
+}
+
+impl Sdf for HalfSpace {
+    fn distance(&self, x: TV) -> T {
+        (x - self.point).dot(&self.normal)
+    }
+
+    fn gradient(&self, _: TV) -> TV {
+        self.normal
+    }
+}
+
+/// See [`Sdf::union`].
+#[derive(Clone, Debug)]
+pub struct Union<A, B>(pub A, pub B);
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, x: TV) -> T {
+        self.0.distance(x).min(self.1.distance(x))
+    }
+}
+
+/// See [`Sdf::intersection`].
+#[derive(Clone, Debug)]
+pub struct Intersection<A, B>(pub A, pub B);
+
+impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+    fn distance(&self, x: TV) -> T {
+        self.0.distance(x).max(self.1.distance(x))
+    }
+}
+
+/// See [`Sdf::difference`].
+#[derive(Clone, Debug)]
+pub struct Difference<A, B>(pub A, pub B);
+
+impl<A: Sdf, B: Sdf> Sdf for Difference<A, B> {
+    fn distance(&self, x: TV) -> T {
+        self.0.distance(x).max(-self.1.distance(x))
+    }
+}
+
+/// See [`Sdf::complement`].
+#[derive(Clone, Debug)]
+pub struct Complement<A>(pub A);
+
+impl<A: Sdf> Sdf for Complement<A> {
+    fn distance(&self, x: TV) -> T {
+        -self.0.distance(x)
+    }
+
+    fn gradient(&self, x: TV) -> TV {
+        -self.0.gradient(x)
+    }
+}
+
+/// A signed distance field sampled at the nodes of a grid, and interpolated multilinearly.
+///
+/// Outside of the grid, the distance to the grid is added to the value at the closest point on the
+/// grid, which overestimates the distance.
+pub struct SampledSdf {
+    pub grid: Grid,
+    /// The distance at each node of the grid.
+    pub values: ArrayNd<T>,
+}
+
+impl SampledSdf {
+    /// Samples another SDF at the nodes of `grid`.
+    pub fn from_sdf<S: Sdf>(grid: Grid, sdf: &S) -> Self {
+        let mut values = ArrayNd::from_element(Range::new(IV::zeros(), grid.num_nodes()), 0.)
+            .expect("the nodes of a grid form a valid domain");
+        for node in grid.nodes() {
+            values[node] = sdf.distance(grid.node_x(node));
+        }
+
+        Self { grid, values }
+    }
+}
+
+impl Sdf for SampledSdf {
+    fn distance(&self, x: TV) -> T {
+        let domain = self.grid.domain;
+        let clamped = x.component_max(&domain.min).component_min(&domain.max);
+
+        let cell = self
+            .grid
+            .cell_index(clamped)
+            .component_max(&IV::zeros())
+            .component_min(&(self.grid.num_cells() - IV::from_element(1)));
+        let frac = (clamped - self.grid.node_x(cell)).component_mul(&self.grid.one_over_dx);
+
+        let corners = Range::new(IV::zeros(), IV::from_element(2));
+        let interpolated: T = RangeIterator::new(corners)
+            .map(|corner| {
+                let weight: T = (0..DIM)
+                    .map(|a| {
+                        if corner[a] == 1 {
+                            frac[a]
+                        } else {
+                            1. - frac[a]
+                        }
+                    })
+                    .product();
+                weight * self.values[cell + corner]
+            })
+            .sum();
+
+        interpolated + (x - clamped).norm()
+    }
+}
+
+/// A static obstacle which particles collide with.
+pub struct Obstacle {
+    pub sdf: Box<dyn Sdf>,
+    /// The coefficient of friction `mu`. The tangential velocity is reduced by up to `mu` times
+    /// the change in normal velocity.
+    pub friction: T,
+    /// The fraction of the normal velocity which is kept (and reversed) in a collision.
+    pub restitution: T,
+}
+
+impl Obstacle {
+    /// Creates a frictionless, inelastic obstacle.
+    pub fn new<S: Sdf + 'static>(sdf: S) -> Self {
+        Self {
+            sdf: Box::new(sdf),
+            friction: 0.,
+            restitution: 0.,
+        }
+    }
+
+    /// Sets the coefficient of friction.
+    pub fn friction(mut self, friction: T) -> Self {
+        self.friction = friction;
+        self
+    }
+
+    /// Sets the coefficient of restitution.
+    pub fn restitution(mut self, restitution: T) -> Self {
+        self.restitution = restitution;
+        self
+    }
+
+    /// Moves a particle inside of the obstacle back to the surface, and corrects its velocity.
+    fn resolve_collision(&self, position: &mut TV, velocity: &mut TV) {
+        let distance = self.sdf.distance(*position);
+        if distance >= 0. {
+            return;
+        }
+
+        let normal = match self.sdf.gradient(*position).try_normalize(T::EPSILON) {
+            Some(normal) => normal,
+            None => return,
+        };
+        *position -= distance * normal;
+
+        let normal_speed = velocity.dot(&normal);
+        if normal_speed >= 0. {
+            return;
+        }
+
+        let tangential = *velocity - normal_speed * normal;
+        let normal_change = -(1. + self.restitution) * normal_speed;
+        let tangential_speed = tangential.norm();
+        let friction_scale = if tangential_speed > 0. {
+            (1. - self.friction * normal_change / tangential_speed).max(0.)
+        } else {
+            0.
+        };
+
+        *velocity = friction_scale * tangential - self.restitution * normal_speed * normal;
+    }
+}
+
+impl SphSimulation {
+    /// Projects particles which have penetrated an obstacle back to its surface.
+    #[instrument(skip_all)]
+    pub(super) fn resolve_obstacle_collisions(&mut self) {
+        let (position, velocity) = self
+            .particles
+            .data_mut()
+            .get_many_mut((POSITION, VELOCITY))
+            .expect("core attributes");
+        for obstacle in &self.obstacles {
+            for (x, v) in position.iter_mut().zip(velocity.iter_mut()) {
+                obstacle.resolve_collision(x, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::{SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_csg_distances() {
+        let sphere = Sphere {
+            center: TV::zeros(),
+            radius: 1.,
+        };
+        let cuboid = Cuboid {
+            range: Range::new(TV::zeros(), TV::from_element(2.)),
+        };
+
+        assert!((sphere.distance(TV::ith(0, 3.)) - 2.).abs() < 1e-12);
+        assert!((cuboid.distance(TV::from_element(1.)) + 1.).abs() < 1e-12);
+        assert!((cuboid.distance(TV::ith(0, -1.)) - 1.).abs() < 1e-12);
+
+        let x = TV::from_element(0.5);
+        let union = sphere.clone().union(cuboid.clone());
+        let intersection = sphere.clone().intersection(cuboid.clone());
+        let difference = cuboid.clone().difference(sphere.clone());
+        assert!(union.distance(x) < 0.);
+        assert!(intersection.distance(x) < 0.);
+        assert!(difference.distance(x) > 0.);
+        assert!(cuboid.complement().distance(x) > 0.);
+
+        let gradient = sphere.gradient(TV::ith(1, 2.));
+        assert!((gradient - TV::ith(1, 1.)).norm() < 1e-12);
+        let gradient = union.gradient(TV::ith(0, -2.));
+        assert!((gradient - TV::ith(0, -1.)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_sampled_sdf() {
+        let sphere = Sphere {
+            center: TV::from_element(0.5),
+            radius: 0.3,
+        };
+        let grid = Grid::new(
+            IV::from_element(20),
+            Range::new(TV::zeros(), TV::from_element(1.)),
+        );
+        let sampled = SampledSdf::from_sdf(grid, &sphere);
+
+        for x in [
+            TV::from_element(0.5),
+            TV::ith(0, 0.3),
+            TV::from_element(0.71),
+        ] {
+            assert!((sampled.distance(x) - sphere.distance(x)).abs() < 0.01);
+        }
+
+        // Outside of the grid the distance is overestimated, but still has the correct sign.
+        let far = TV::ith(0, 2.);
+        assert!(sampled.distance(far) >= sphere.distance(far));
+    }
+
+    #[test]
+    fn test_particles_stay_outside_of_obstacle() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.3)),
+            delta_time: 4e-3,
+            gravity: TV::ith(1, -9.81),
+            ..Default::default()
+        };
+        let center = TV::ith(1, 0.1) + TV::ith(0, 0.15);
+        let sphere = Sphere {
+            center,
+            radius: 0.06,
+        };
+        let fill = Range::new(
+            TV::ith(0, 0.1) + TV::ith(1, 0.2),
+            TV::from_element(0.2) + TV::ith(1, 0.04),
+        );
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(fill)
+            .obstacle(Obstacle::new(sphere.clone()).friction(0.5))
+            .build()
+            .unwrap();
+
+        for _ in 0..50 {
+            sim.advance_timestep();
+            assert!(sim
+                .particles
+                .position()
+                .iter()
+                .all(|&x| sphere.distance(x) >= -1e-9));
+        }
+    }
+
+    #[test]
+    fn test_restitution() {
+        let floor = HalfSpace {
+            point: TV::zeros(),
+            normal: TV::ith(1, 1.),
+        };
+        let obstacle = Obstacle::new(floor).restitution(0.5);
+        let mut x = TV::ith(1, -0.1);
+        let mut v = TV::ith(1, -2.) + TV::ith(0, 1.);
+        obstacle.resolve_collision(&mut x, &mut v);
+
+        assert!(x[1].abs() < 1e-12);
+        assert!((v - (TV::ith(1, 1.) + TV::ith(0, 1.))).norm() < 1e-12);
+    }
+}
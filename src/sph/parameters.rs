@@ -1,28 +1,92 @@
-use crate::base::Range;
+use super::{AdaptiveTimeStep, KernelType, PbfParameters, Phase, Rheology, ThermalParameters};
+use crate::base::{PeriodicDomain, Periodicity, Range};
 use crate::math::*;
+use crate::neighbors::NeighborSearchType;
+use crate::util::integrators::Integrator;
 
 /// A struct containing all of the user-tunable parameters for the SPH simulation
 ///
 /// These must be set before starting a simulation.
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct SphParamaters {
+    /// The number of particles, which is updated as particles are emitted and removed.
     pub num_particles: usize,
     /// The time step
     pub delta_time: T,
+    /// If set, the time step is chosen in every step from stability limits, and `delta_time`
+    /// holds the time step of the last step.
+    pub adaptive_time_step: Option<AdaptiveTimeStep>,
     /// The radius of the smoothing kernel,
     pub h: T,
     /// The density of the fluid without any forces
     pub rest_density: T,
+    /// If not empty, the particles belong to these fluids, according to their
+    /// [`super::multiphase::PHASE`] attribute, and take their rest densities and viscosities from
+    /// them.
+    ///
+    /// Multiple phases are only supported by [`PressureSolver::StateEquation`], usually with the
+    /// Tait equation of state and `clamp_negative_pressure`. The other solvers, including the
+    /// default DFSPH solver, use mass-weighted density sums, so creating a simulation with them
+    /// fails with [`super::SphSimulationError::MultiphaseSolver`].
+    pub phases: Vec<Phase>,
     /// The ideal gas constant used in the state equation pressure solver
     pub k: T,
-    /// The viscosity constant
+    /// The equation of state used to compute pressures from densities
+    pub equation_of_state: EquationOfState,
+    /// The method used to compute pressures
+    pub pressure_solver: PressureSolver,
+    /// The time integrator used with the state equation solver. The other pressure solvers
+    /// predict the particle motion as part of the solve, and always use symplectic Euler.
+    pub integrator: Integrator,
+    /// Whether negative pressures are set to zero. This avoids particles clumping together where
+    /// the density is below the rest density (for example, at the free surface).
+    pub clamp_negative_pressure: bool,
+    /// The viscosity constant of the Laplacian viscosity model
     pub mu: T,
+    /// The model used to compute viscosity forces. This is ignored by PBF, which only uses XSPH.
+    pub viscosity: ViscosityModel,
+    /// If set, every particle gets its own viscosity from its shear rate, which replaces `mu` in
+    /// the Laplacian and implicit viscosity models.
+    pub rheology: Option<Rheology>,
+    /// The XSPH coefficient `c`, which blends each velocity with the velocities of its neighbors
+    /// at the end of every step. PBF uses [`PbfParameters::xsph`] instead.
+    pub xsph: T,
+    /// The surface tension coefficient `gamma` of the cohesion and curvature forces. See
+    /// [`super::surface_tension`].
+    pub surface_tension: T,
+    /// The adhesion coefficient `beta`, which sets how strongly the fluid sticks to the boundary
+    pub adhesion: T,
     /// The force of gravity
     pub gravity: TV,
+    /// If set, the particles carry a temperature, which diffuses and makes the fluid buoyant. See
+    /// [`super::thermal`].
+    pub thermal: Option<ThermalParameters>,
     /// The velocity damping at the boundary for the reflection boundary conditions
     pub velocity_damping: T,
     /// The simulation domain
     pub domain: Range<TV>,
+    /// The axes along which the domain wraps around. Particles which leave the domain on one side
+    /// re-enter it on the other, and interact with the particles near the opposite side.
+    pub periodicity: Periodicity,
+    /// With the `parallel` feature, whether sums over all particles are computed in a fixed order.
+    /// This makes the results bit-identical to serial execution, at a small cost.
+    pub deterministic: bool,
+    /// The data structure used to find neighboring particles
+    pub neighbor_search: NeighborSearchType,
+    /// If set, neighbor lists are built with radius `h` plus this skin distance, and reused until
+    /// some particle has moved more than half the skin. Otherwise, the neighbors are searched for
+    /// every time they are needed.
+    pub verlet_skin: Option<T>,
+    /// If set, the particles are sorted along a Z-order curve every this many steps, so that
+    /// neighboring particles are close together in memory. See
+    /// [`super::SphSimulation::last_reordering`].
+    pub reorder_interval: Option<usize>,
+    /// The kernel used to compute densities
+    pub density_kernel: KernelType,
+    /// The kernel whose gradient is used to compute pressure forces
+    pub pressure_kernel: KernelType,
+    /// The kernel whose Laplacian is used to compute viscosity forces
+    pub viscosity_kernel: KernelType,
 }
 
 impl Default for SphParamaters {
@@ -30,13 +94,220 @@ impl Default for SphParamaters {
         Self {
             num_particles: 0,
             delta_time: 0.01,
+            adaptive_time_step: None,
             h: 0.04,
             rest_density: 1000.,
+            phases: Vec::new(),
             k: 4.,
+            equation_of_state: EquationOfState::IdealGas,
+            clamp_negative_pressure: false,
+            pressure_solver: PressureSolver::default(),
+            integrator: Integrator::default(),
             mu: 8.,
+            viscosity: ViscosityModel::Laplacian,
+            rheology: None,
+            xsph: 0.,
+            surface_tension: 0.,
+            adhesion: 0.,
             gravity: TV::ith(1, -1.),
+            thermal: None,
             velocity_damping: 0.8,
             domain: Range::new(TV::zeros(), TV::from_element(3.)),
+            periodicity: Periodicity::NONE,
+            deterministic: true,
+            neighbor_search: NeighborSearchType::default(),
+            verlet_skin: None,
+            reorder_interval: None,
+            density_kernel: KernelType::Poly6,
+            pressure_kernel: KernelType::Spiky,
+            viscosity_kernel: KernelType::Viscosity,
         }
     }
 }
+
+/// The method used to compute the pressures in each time step. Only `StateEquation` supports
+/// multiple phases.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum PressureSolver {
+    /// Computes the pressures directly from the densities, using the `equation_of_state`.
+    StateEquation,
+    /// Predictive-corrective incompressible SPH [Solenthaler and Pajarola 2009]. Pressures are
+    /// corrected until the average density error is below the tolerance.
+    Pcisph {
+        /// The tolerated average density error, relative to the rest density (e.g. 0.01 for 1%)
+        tolerance: T,
+        /// The maximum number of correction iterations in each time step
+        max_iterations: usize,
+    },
+    /// Implicit incompressible SPH [Ihmsen et al. 2014]. The pressure Poisson equation is solved
+    /// with relaxed Jacobi iterations until the average density error is below the tolerance.
+    Iisph {
+        /// The tolerated average density error, relative to the rest density (e.g. 0.01 for 1%)
+        tolerance: T,
+        /// The maximum number of Jacobi iterations in each time step
+        max_iterations: usize,
+        /// The relaxation factor for the Jacobi iterations, usually 0.5
+        omega: T,
+    },
+    /// Divergence-free SPH [Bender and Koschier 2015]. Each step runs a divergence-free solver,
+    /// which removes the velocity divergence, and a constant density solver, which removes the
+    /// density error of the predicted positions.
+    Dfsph {
+        /// The tolerated average density error, relative to the rest density (e.g. 0.01 for 1%)
+        tolerance: T,
+        /// The tolerated average rate of change of the density, relative to the rest density and
+        /// per time step
+        divergence_tolerance: T,
+        /// The maximum number of iterations of each solver in each time step
+        max_iterations: usize,
+    },
+    /// Position Based Fluids [Macklin and Müller 2013]. Rather than computing pressures, the
+    /// predicted positions are projected to satisfy a density constraint. The viscosity is given
+    /// by XSPH rather than `mu`.
+    Pbf(PbfParameters),
+}
+
+impl Default for PressureSolver {
+    fn default() -> Self {
+        PressureSolver::Dfsph {
+            tolerance: 0.001,
+            divergence_tolerance: 0.01,
+            max_iterations: 100,
+        }
+    }
+}
+
+/// The model used to compute viscosity forces.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ViscosityModel {
+    /// The Laplacian of the velocity field scaled by `mu` [Müller et al. 2003]. The forces between
+    /// two particles are not along the line between them, so angular momentum is not conserved.
+    #[default]
+    Laplacian,
+    /// Artificial viscosity [Monaghan 1997], with the signal velocity formulation
+    /// `Pi_ij = -alpha v_sig w_ij / (2 rho_ij)`, where `w_ij` is the rate at which particles `i`
+    /// and `j` approach each other, `v_sig = 2 c - beta w_ij` and `rho_ij` is their average
+    /// density. Only approaching particles are affected, and the forces act along the line between
+    /// them, so both linear and angular momentum are conserved.
+    Artificial {
+        /// The linear coefficient `alpha`, usually between 0.01 and 1
+        alpha: T,
+        /// The quadratic coefficient `beta`, which prevents particles from passing through each
+        /// other in strong shocks, usually 2 alpha or 0
+        beta: T,
+        /// The numerical speed of sound `c`
+        speed_of_sound: T,
+    },
+    /// The viscosity `mu` integrated implicitly [Weiler et al. 2018], which remains stable for
+    /// very viscous fluids such as honey or lava. The new velocities are found by solving
+    /// `(I - dt mu / rho_i L) v = v*` with a matrix-free conjugate gradient solver, where `v*` are
+    /// the velocities after the other non-pressure forces, and `L` is an SPH Laplacian which only
+    /// uses the relative velocities along the lines between particles.
+    Implicit {
+        /// The tolerated residual of the linear system, relative to the right hand side
+        tolerance: T,
+        /// The maximum number of conjugate gradient iterations in each time step
+        max_iterations: usize,
+    },
+}
+
+/// The equation of state relating pressure to density.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum EquationOfState {
+    /// The ideal gas law `k * (rho - rho_0)` from [Müller et al. 2003]. Pressure forces are computed
+    /// using the average of the pressures of the two particles.
+    IdealGas,
+    /// The Tait equation `B * ((rho / rho_0)^gamma - 1)` used in weakly compressible SPH (WCSPH)
+    /// [Becker and Teschner 2007]. Pressure forces are computed using the symmetric formulation
+    /// `-rho_i * sum_j m_j (p_i / rho_i^2 + p_j / rho_j^2) grad W_ij`, which conserves momentum.
+    Tait {
+        /// The stiffness `B`. If this is `None`, it is derived from the speed of sound as
+        /// `rho_0 * c^2 / gamma`.
+        stiffness: Option<T>,
+        /// The exponent `gamma`, usually 7
+        gamma: T,
+        /// The numerical speed of sound `c`. Density fluctuations are roughly proportional to
+        /// `(v_max / c)^2`, so `c` should be about 10 times the largest expected velocity for
+        /// density errors around 1%.
+        speed_of_sound: T,
+    },
+}
+
+impl EquationOfState {
+    /// Creates a Tait equation of state with `gamma = 7` and the stiffness derived from the speed
+    /// of sound.
+    pub fn tait(speed_of_sound: T) -> Self {
+        EquationOfState::Tait {
+            stiffness: None,
+            gamma: 7.,
+            speed_of_sound,
+        }
+    }
+}
+
+impl SphParamaters {
+    /// The simulation domain, along with the axes which wrap around.
+    pub fn periodic_domain(&self) -> PeriodicDomain {
+        PeriodicDomain::new(self.domain, self.periodicity)
+    }
+
+    /// The rest density of the given phase, or `rest_density` if there are no phases.
+    pub fn phase_rest_density(&self, phase: usize) -> T {
+        self.phases
+            .get(phase)
+            .map_or(self.rest_density, |phase| phase.rest_density)
+    }
+
+    /// Computes the pressure at a particular density, using the equation of state.
+    pub fn pressure(&self, density: T) -> T {
+        self.pressure_with_rest_density(density, self.rest_density)
+    }
+
+    /// Computes the pressure at a particular density for a fluid with the given rest density, as
+    /// in a multiphase simulation.
+    pub fn pressure_with_rest_density(&self, density: T, rest_density: T) -> T {
+        let pressure = match self.equation_of_state {
+            EquationOfState::IdealGas => self.k * (density - rest_density),
+            EquationOfState::Tait {
+                stiffness,
+                gamma,
+                speed_of_sound,
+            } => {
+                let stiffness =
+                    stiffness.unwrap_or(rest_density * speed_of_sound * speed_of_sound / gamma);
+                stiffness * ((density / rest_density).powf(gamma) - 1.)
+            }
+        };
+
+        if self.clamp_negative_pressure {
+            pressure.max(0.)
+        } else {
+            pressure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tait_pressure() {
+        let params = SphParamaters {
+            equation_of_state: EquationOfState::tait(10.),
+            ..Default::default()
+        };
+        let stiffness = 1000. * 100. / 7.;
+
+        assert_eq!(params.pressure(1000.), 0.);
+        let expected = stiffness * (1.01f64.powi(7) - 1.);
+        assert!((params.pressure(1010.) - expected).abs() < 1e-9);
+        assert!(params.pressure(990.) < 0.);
+
+        let clamped = SphParamaters {
+            clamp_negative_pressure: true,
+            ..params
+        };
+        assert_eq!(clamped.pressure(990.), 0.);
+    }
+}
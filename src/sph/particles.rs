@@ -1,12 +1,179 @@
+use crate::base::{Attribute, AttributeValue, ParticleData};
 use crate::math::*;
+use crate::util::z_order::Permutation;
 
-/// Contains all SPH particle data
+pub const MASS: Attribute<T> = Attribute::new("mass");
+pub const DENSITY: Attribute<T> = Attribute::new("density");
+/// The rest density of each particle, which depends on its phase in multiphase simulations.
+pub const REST_DENSITY: Attribute<T> = Attribute::new("rest_density");
+pub const PRESSURE: Attribute<T> = Attribute::new("pressure");
+/// The DFSPH factor relating density errors to pressures, which only depends on the particle
+/// positions. See [Bender and Koschier 2015].
+pub const ALPHA: Attribute<T> = Attribute::new("alpha");
+
+pub const POSITION: Attribute<TV> = Attribute::new("position");
+pub const VELOCITY: Attribute<TV> = Attribute::new("velocity");
+pub const FORCE: Attribute<TV> = Attribute::new("force");
+/// The surface normal from the gradient of the color field, which is only computed with surface
+/// tension.
+pub const NORMAL: Attribute<TV> = Attribute::new("normal");
+/// The dynamic viscosity of each particle, from `params.rheology` or `params.mu`.
+pub const VISCOSITY: Attribute<T> = Attribute::new("viscosity");
+
+/// Contains all SPH particle data.
+///
+/// Every per-particle quantity is a column of a single [`ParticleData`], so particles are added,
+/// removed and reordered in one place. The core columns listed above always exist and have typed
+/// accessors such as [`SphParticles::mass`]. Additional data, such as temperatures or phase IDs,
+/// can be added through [`SphParticles::data_mut`].
+#[derive(Clone, Debug)]
 pub struct SphParticles {
-    pub mass: Vec<T>,
-    pub density: Vec<T>,
-    pub pressure: Vec<T>,
+    data: ParticleData,
+}
+
+macro_rules! core_attributes {
+    ($($attribute:ident: $X:ty => $get:ident, $get_mut:ident;)*) => {
+        impl Default for SphParticles {
+            fn default() -> Self {
+                let mut data = ParticleData::new();
+                $(
+                    data.add_attribute($attribute, <$X>::default())
+                        .expect("core attributes have unique names");
+                )*
+                Self { data }
+            }
+        }
+
+        impl SphParticles {
+            $(
+                #[doc = concat!("The values of [`", stringify!($attribute), "`].")]
+                pub fn $get(&self) -> &[$X] {
+                    self.column($attribute)
+                }
+
+                #[doc = concat!("The values of [`", stringify!($attribute), "`], mutably.")]
+                pub fn $get_mut(&mut self) -> &mut [$X] {
+                    self.column_mut($attribute)
+                }
+            )*
+        }
+    };
+}
+
+core_attributes! {
+    MASS: T => mass, mass_mut;
+    DENSITY: T => density, density_mut;
+    REST_DENSITY: T => rest_density, rest_density_mut;
+    PRESSURE: T => pressure, pressure_mut;
+    ALPHA: T => alpha, alpha_mut;
+    POSITION: TV => position, position_mut;
+    VELOCITY: TV => velocity, velocity_mut;
+    FORCE: TV => force, force_mut;
+    NORMAL: TV => normal, normal_mut;
+    VISCOSITY: T => viscosity, viscosity_mut;
+}
+
+impl SphParticles {
+    /// Adds a new particle with the given mass, position and velocity. All other quantities have
+    /// their default values until they are computed by the simulation.
+    pub fn push(&mut self, mass: T, position: TV, velocity: TV) {
+        let i = self.data.push();
+        self.mass_mut()[i] = mass;
+        self.position_mut()[i] = position;
+        self.velocity_mut()[i] = velocity;
+    }
+
+    /// Removes particle `i`, replacing it with the last particle.
+    pub fn swap_remove(&mut self, i: usize) {
+        self.data.swap_remove(i);
+    }
+
+    /// Removes the particles for which `keep(i)` is false, keeping the rest in order.
+    pub fn retain<F: FnMut(usize) -> bool>(&mut self, keep: F) {
+        self.data.retain(keep);
+    }
+
+    /// Returns the number of particles.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if there are no particles.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Reorders all particle data by `permutation`.
+    pub fn permute(&mut self, permutation: &Permutation) {
+        self.data.gather(permutation.sources());
+    }
+
+    /// All particle data, including the core attributes.
+    pub fn data(&self) -> &ParticleData {
+        &self.data
+    }
+
+    /// All particle data, for adding or modifying custom attributes. The core attributes must not
+    /// be removed.
+    pub fn data_mut(&mut self) -> &mut ParticleData {
+        &mut self.data
+    }
+
+    /// Moves the values of an attribute out, so that they can be modified while the other
+    /// particle data is borrowed. See [`ParticleData::take`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the attribute does not exist.
+    pub fn take<X: AttributeValue>(&mut self, attribute: Attribute<X>) -> Vec<X> {
+        self.data
+            .take(attribute)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Puts back the values of an attribute which were moved out with [`SphParticles::take`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the attribute does not exist or `values` has the wrong length.
+    pub fn restore<X: AttributeValue>(&mut self, attribute: Attribute<X>, values: Vec<X>) {
+        self.data
+            .restore(attribute, values)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn column<X: AttributeValue>(&self, attribute: Attribute<X>) -> &[X] {
+        self.data.get(attribute).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn column_mut<X: AttributeValue>(&mut self, attribute: Attribute<X>) -> &mut [X] {
+        self.data
+            .get_mut(attribute)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABEL: Attribute<usize> = Attribute::new("label");
+
+    #[test]
+    fn test_custom_attributes_follow_particles() {
+        let mut particles = SphParticles::default();
+        particles.data_mut().add_attribute(LABEL, 0).unwrap();
+        for i in 0..4 {
+            particles.push(i as T, TV::from_element(i as T), TV::zeros());
+            particles.data_mut().get_mut(LABEL).unwrap()[i] = i;
+        }
 
-    pub position: Vec<TV>,
-    pub velocity: Vec<TV>,
-    pub force: Vec<TV>,
+        particles.swap_remove(0);
+        particles.retain(|i| i != 1);
+        assert_eq!(particles.len(), 2);
+        assert_eq!(particles.mass(), &[3., 2.]);
+        assert_eq!(particles.data().get(LABEL).unwrap(), &[3, 2]);
+        assert_eq!(particles.position()[1], TV::from_element(2.));
+        assert_eq!(particles.density(), &[0., 0.]);
+    }
 }
@@ -0,0 +1,449 @@
+//! Position Based Fluids (PBF), from
+//!
+//! * Macklin, M., & Müller, M. (2013). Position based fluids. ACM Transactions on Graphics (TOG), 32(4), 1-12.
+//!
+//! Rather than computing pressure forces, PBF predicts the new particle positions and projects them
+//! to satisfy a density constraint `C_i = rho_i / rho_0 - 1 = 0` for every particle, using a fixed
+//! number of Jacobi iterations. The velocities are then derived from the change in position. Since
+//! the constraint projection never adds energy, this remains stable even for large time steps.
+
+use super::particles::{DENSITY, POSITION, VELOCITY};
+use super::{SolverStats, SphSimulation};
+use crate::math::*;
+use crate::neighbors::NeighborLists;
+use tracing::{instrument, trace};
+
+/// The parameters of the PBF solver. See [`super::PressureSolver::Pbf`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PbfParameters {
+    /// The number of constraint projection iterations in each time step
+    pub iterations: usize,
+    /// The constraint force mixing parameter `epsilon`, added to the denominator of `lambda`.
+    /// Larger values soften the density constraint. This has units of 1 / length^2, and should be
+    /// small compared to `sum_k |grad_k C_i|^2`.
+    pub relaxation: T,
+    /// The strength `k` of the artificial pressure `s_corr` which prevents clustering
+    pub tensile_k: T,
+    /// The exponent `n` of the artificial pressure
+    pub tensile_n: i32,
+    /// The distance `delta q` at which the artificial pressure is `k`, as a fraction of `h`
+    pub tensile_dq: T,
+    /// The XSPH viscosity coefficient `c`
+    pub xsph: T,
+    /// The vorticity confinement coefficient `epsilon`
+    pub vorticity: T,
+}
+
+impl Default for PbfParameters {
+    fn default() -> Self {
+        Self {
+            iterations: 4,
+            relaxation: 100.,
+            tensile_k: 0.1,
+            tensile_n: 4,
+            tensile_dq: 0.2,
+            xsph: 0.01,
+            vorticity: 0.,
+        }
+    }
+}
+
+/// Embeds a vector in 3d, so that cross products can be used in both 2d and 3d. In 2d, the curl of
+/// a vector field only has a z-component.
+fn embed(v: TV) -> na::Vector3<T> {
+    na::Vector3::from_fn(|i, _| if i < DIM { v[i] } else { 0. })
+}
+
+/// The inverse of `embed`, dropping the components which don't exist in 2d.
+fn project(v: na::Vector3<T>) -> TV {
+    TV::from_fn(|i, _| v[i])
+}
+
+impl SphSimulation {
+    /// Advances the particles with Position Based Fluids.
+    ///
+    /// The external forces must already have been applied. Unlike the other solvers, this moves
+    /// the particles itself.
+    #[instrument(skip_all)]
+    pub(super) fn pbf_step(&mut self, pbf: &PbfParameters) -> SolverStats {
+        let n = self.params.num_particles;
+        let dt = self.params.delta_time;
+
+        // Predict the new positions using the external forces.
+        let old_position = self.particles.position().to_vec();
+        let mut velocity = self.particles.take(VELOCITY);
+        let mut position = self.particles.take(POSITION);
+        let (force, density) = (self.particles.force(), self.particles.density());
+        for i in 0..n {
+            velocity[i] += dt * force[i] / density[i];
+            position[i] += dt * velocity[i];
+        }
+        self.particles.restore(VELOCITY, velocity);
+        self.particles.restore(POSITION, position);
+        self.clamp_to_domain();
+
+        // The neighbors are found using the predicted positions, and are kept fixed during the
+        // constraint projection.
+        self.fill_cells();
+        let neighbors = self.neighbor_lists();
+        let boundary_neighbors = self.boundary_neighbor_lists();
+
+        let mut stats = SolverStats::default();
+        for _ in 0..pbf.iterations {
+            stats.density_error = self.pbf_project(pbf, &neighbors, &boundary_neighbors);
+            stats.iterations += 1;
+            trace!(stats.iterations, stats.density_error);
+        }
+
+        let (position, velocity) = self
+            .particles
+            .data_mut()
+            .get_many_mut((POSITION, VELOCITY))
+            .expect("core attributes");
+        for (velocity, (x, old_x)) in velocity.iter_mut().zip(position.iter().zip(&old_position)) {
+            *velocity = (x - old_x) / dt;
+        }
+
+        self.apply_vorticity_confinement(pbf.vorticity, &neighbors);
+        self.apply_xsph(pbf.xsph, &neighbors);
+
+        stats
+    }
+
+    /// Performs one Jacobi iteration of the density constraint projection, and returns the average
+    /// density error before the projection.
+    ///
+    /// The boundary particles contribute to the density, but are never moved. The corrections
+    /// they cause are recorded as forces on the boundary.
+    fn pbf_project(
+        &mut self,
+        pbf: &PbfParameters,
+        neighbors: &NeighborLists,
+        boundary_neighbors: &NeighborLists,
+    ) -> T {
+        let n = self.params.num_particles;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let dt = self.params.delta_time;
+        let rest_density = self.params.rest_density;
+        let density_kernel = self.params.density_kernel;
+        let gradient_kernel = self.params.pressure_kernel;
+
+        let boundary = &mut self.boundary;
+        let mut densities = self.particles.take(DENSITY);
+        let particles = &self.particles;
+        let mass = particles.mass();
+        let position = particles.position();
+
+        let mut lambda = vec![0.; n];
+        let mut denominator = vec![0.; n];
+        let mut total_error = 0.;
+        for i in 0..n {
+            let x = position[i];
+            let mut density = 0.;
+            let mut grad_i = TV::zeros();
+            let mut sum_grad_squared = 0.;
+            for &j in &neighbors[i] {
+                let r = periodic.displacement(x, position[j]);
+                density += mass[j] * density_kernel.value(r, h);
+                if j != i {
+                    let grad_j = mass[j] / rest_density * gradient_kernel.gradient(r, h);
+                    grad_i += grad_j;
+                    sum_grad_squared += grad_j.norm_squared();
+                }
+            }
+            for &b in &boundary_neighbors[i] {
+                let r = periodic.displacement(x, boundary.position[b]);
+                density += boundary.psi[b] * density_kernel.value(r, h);
+                grad_i += boundary.psi[b] / rest_density * gradient_kernel.gradient(r, h);
+            }
+            densities[i] = density;
+
+            // Only compression is corrected, since particles at the free surface have incomplete
+            // neighborhoods.
+            let constraint = (density / rest_density - 1.).max(0.);
+            total_error += constraint;
+            denominator[i] = grad_i.norm_squared() + sum_grad_squared + pbf.relaxation;
+            lambda[i] = -constraint / denominator[i];
+        }
+
+        // The artificial pressure is treated as an additional constraint violation of
+        // `k * (W(r) / W(delta q))^n`, so it is scaled in the same way as `lambda`. The average
+        // denominator of the pair is used, so that the correction is the same for `i` and `j`, and
+        // the position update conserves momentum.
+        let w_dq = density_kernel.value(TV::ith(0, pbf.tensile_dq * h), h);
+        let delta: Vec<TV> = (0..n)
+            .map(|i| {
+                let x = position[i];
+                neighbors[i]
+                    .iter()
+                    .filter(|&&j| j != i)
+                    .map(|&j| {
+                        let r = periodic.displacement(x, position[j]);
+                        let s_corr = if w_dq > 0. {
+                            let ratio = density_kernel.value(r, h) / w_dq;
+                            let scale = 0.5 * (denominator[i] + denominator[j]);
+                            -pbf.tensile_k * ratio.powi(pbf.tensile_n) / scale
+                        } else {
+                            0.
+                        };
+                        mass[j] * (lambda[i] + lambda[j] + s_corr) * gradient_kernel.gradient(r, h)
+                    })
+                    .sum::<TV>()
+                    / rest_density
+            })
+            .collect();
+
+        let delta: Vec<TV> = (0..n)
+            .map(|i| {
+                let x = position[i];
+                let mut boundary_delta = TV::zeros();
+                for &b in &boundary_neighbors[i] {
+                    let grad =
+                        gradient_kernel.gradient(periodic.displacement(x, boundary.position[b]), h);
+                    let delta_b = boundary.psi[b] * lambda[i] * grad / rest_density;
+                    // The force needed to move the particle by `delta_b` over the time step.
+                    boundary.force[b] -= mass[i] * delta_b / (dt * dt);
+                    boundary_delta += delta_b;
+                }
+                delta[i] + boundary_delta
+            })
+            .collect();
+
+        self.particles.restore(DENSITY, densities);
+        for (x, delta) in self.particles.position_mut().iter_mut().zip(&delta) {
+            *x += delta;
+        }
+        self.clamp_to_domain();
+
+        total_error / n.max(1) as T
+    }
+
+    /// Adds the vorticity confinement acceleration `epsilon * (N x omega)`, which reintroduces
+    /// rotational motion lost to numerical damping.
+    fn apply_vorticity_confinement(&mut self, epsilon: T, neighbors: &NeighborLists) {
+        if epsilon == 0. {
+            return;
+        }
+
+        let n = self.params.num_particles;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let dt = self.params.delta_time;
+        let kernel = self.params.pressure_kernel;
+
+        let mut new_velocity = self.particles.velocity().to_vec();
+        let particles = &self.particles;
+        let mass = particles.mass();
+        let density = particles.density();
+        let position = particles.position();
+        let velocity = particles.velocity();
+
+        let vorticity: Vec<na::Vector3<T>> = (0..n)
+            .map(|i| {
+                neighbors[i]
+                    .iter()
+                    .map(|&j| {
+                        let v_ij = embed(velocity[j] - velocity[i]);
+                        // The gradient with respect to `x_j`, so that this is the curl of the
+                        // velocity rather than its negative.
+                        let grad = -embed(
+                            kernel.gradient(periodic.displacement(position[i], position[j]), h),
+                        );
+                        mass[j] / density[j] * v_ij.cross(&grad)
+                    })
+                    .sum()
+            })
+            .collect();
+
+        for i in 0..n {
+            let omega_i = vorticity[i].norm();
+            let eta = neighbors[i]
+                .iter()
+                .map(|&j| {
+                    let grad = kernel.gradient(periodic.displacement(position[i], position[j]), h);
+                    mass[j] / density[j] * (vorticity[j].norm() - omega_i) * grad
+                })
+                .sum::<TV>();
+
+            if let Some(normal) = eta.try_normalize(T::EPSILON) {
+                let acceleration = epsilon * project(embed(normal).cross(&vorticity[i]));
+                new_velocity[i] += dt * acceleration;
+            }
+        }
+        self.particles.restore(VELOCITY, new_velocity);
+    }
+
+    /// Moves particles outside of the domain back to the boundary, except along periodic axes,
+    /// where they are wrapped around at the end of the step.
+    fn clamp_to_domain(&mut self) {
+        let domain = self.params.domain;
+        let periodicity = self.params.periodicity;
+        for x in self.particles.position_mut().iter_mut() {
+            for a in (0..DIM).filter(|&a| !periodicity.is_periodic(a)) {
+                x[a] = x[a].max(domain.min[a]).min(domain.max[a]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PbfParameters;
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::particles::{POSITION, VELOCITY};
+    use crate::sph::{PressureSolver, SphParamaters, SphSimulation, SphSimulationBuilder};
+
+    fn pbf_simulation(pbf: PbfParameters, gravity: T, region: Range<TV>) -> SphSimulation {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 0.005,
+            gravity: TV::ith(1, gravity),
+            pressure_solver: PressureSolver::Pbf(pbf),
+            ..Default::default()
+        };
+        SphSimulationBuilder::new(params)
+            .fill_box(region)
+            .build()
+            .unwrap()
+    }
+
+    /// A blob in the middle of the domain, rotating about its center.
+    fn rotating_blob(pbf: PbfParameters) -> SphSimulation {
+        let center = TV::from_element(0.25);
+        let blob = Range::new(center, center).thickened(0.1);
+        let mut sim = pbf_simulation(pbf, 0., blob);
+        let (position, velocity) = sim
+            .particles
+            .data_mut()
+            .get_many_mut((POSITION, VELOCITY))
+            .unwrap();
+        for (v, x) in velocity.iter_mut().zip(position.iter()) {
+            let r = x - center;
+            *v = 10. * TV::from_fn(|a, _| [-r[1], r[0], 0.].get(a).copied().unwrap_or(0.));
+        }
+        sim
+    }
+
+    fn kinetic_energy(sim: &SphSimulation) -> T {
+        let particles = &sim.particles;
+        particles
+            .velocity()
+            .iter()
+            .zip(particles.mass())
+            .map(|(v, m)| 0.5 * m * v.norm_squared())
+            .sum()
+    }
+
+    #[test]
+    fn test_pbf_large_time_step() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 0.01,
+            gravity: TV::ith(1, -9.81),
+            pressure_solver: PressureSolver::Pbf(PbfParameters {
+                vorticity: 1e-4,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params.clone())
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        for _ in 0..30 {
+            sim.advance_timestep();
+            assert!(sim.solver_stats.unwrap().density_error < 0.1);
+        }
+
+        assert!(sim
+            .particles
+            .position()
+            .iter()
+            .all(|&x| params.domain.contains(x)));
+        assert!(sim.particles.velocity().iter().all(|v| v.norm() < 10.));
+    }
+
+    #[test]
+    fn test_pbf_tensile_correction_prevents_clustering() {
+        let block = Range::new(
+            TV::zeros(),
+            TV::from_fn(|a, _| if a == 0 { 0.2 } else { 0.1 }),
+        );
+        let clustered_particles = |tensile_k| {
+            let pbf = PbfParameters {
+                tensile_k,
+                ..Default::default()
+            };
+            let mut sim = pbf_simulation(pbf, -9.81, block);
+            for _ in 0..100 {
+                sim.advance_timestep();
+            }
+
+            let position = sim.particles.position();
+            let h = sim.params.h;
+            (0..position.len())
+                .filter(|&i| {
+                    (0..position.len())
+                        .any(|j| j != i && (position[i] - position[j]).norm() < 0.25 * h)
+                })
+                .count()
+        };
+
+        // Only compression is corrected, so without the artificial pressure, the particles at the
+        // free surface pull each other together.
+        assert!(clustered_particles(0.) > 0);
+        assert_eq!(clustered_particles(0.1), 0);
+    }
+
+    #[test]
+    fn test_pbf_vorticity_confinement_adds_rotation() {
+        let energy = |vorticity| {
+            let mut sim = rotating_blob(PbfParameters {
+                vorticity,
+                xsph: 0.,
+                ..Default::default()
+            });
+            for _ in 0..20 {
+                sim.advance_timestep();
+            }
+            kinetic_energy(&sim)
+        };
+
+        let damped = energy(0.);
+        let confined = energy(0.01);
+        assert!(confined > 1.005 * damped, "{confined} vs {damped}");
+    }
+
+    #[test]
+    fn test_pbf_xsph_smooths_velocities() {
+        let spread = |xsph| {
+            let center = TV::from_element(0.25);
+            let blob = Range::new(center, center).thickened(0.1);
+            let mut sim = pbf_simulation(
+                PbfParameters {
+                    xsph,
+                    ..Default::default()
+                },
+                0.,
+                blob,
+            );
+            // Pseudo-random velocities in [-1, 1].
+            for (i, v) in sim.particles.velocity_mut().iter_mut().enumerate() {
+                *v = TV::from_fn(|a, _| (1e4 * ((DIM * i + a) as T).sin()).fract());
+            }
+            sim.advance_timestep();
+
+            let velocity = sim.particles.velocity();
+            let mean = velocity.iter().sum::<TV>() / velocity.len() as T;
+            velocity
+                .iter()
+                .map(|v| (v - mean).norm_squared())
+                .sum::<T>()
+        };
+
+        assert!(spread(0.1) < 0.95 * spread(0.));
+    }
+}
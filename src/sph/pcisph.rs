@@ -0,0 +1,189 @@
+//! Predictive-corrective incompressible SPH (PCISPH), from
+//!
+//! * Solenthaler, B., & Pajarola, R. (2009). Predictive-corrective incompressible SPH. In ACM SIGGRAPH 2009 papers (pp. 1-6).
+//!
+//! Rather than computing pressures from an equation of state, PCISPH predicts where the particles
+//! would move under the current pressures, and corrects the pressures in proportion to the
+//! resulting density error. This is repeated until the density error is below a tolerance, which
+//! allows much larger time steps than a stiff equation of state.
+
+use super::particles::{FORCE, PRESSURE};
+use super::{SolverStats, SphParamaters, SphSimulation};
+use crate::base::{Range, RangeIterator};
+use crate::math::*;
+use crate::util::parallel;
+use tracing::{instrument, trace};
+
+/// The minimum number of iterations, since the first iterations are not yet accurate enough to
+/// judge convergence.
+const MIN_ITERATIONS: usize = 3;
+
+impl SphSimulation {
+    /// Computes pressures with PCISPH, and adds the resulting pressure forces to the particles.
+    ///
+    /// The non-pressure forces must already have been applied.
+    #[instrument(skip_all)]
+    pub(super) fn pcisph_solve(&mut self, tolerance: T, max_iterations: usize) -> SolverStats {
+        let n = self.params.num_particles;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let dt = self.params.delta_time;
+        let rest_density = self.params.rest_density;
+        let density_kernel = self.params.density_kernel;
+        let pressure_kernel = self.params.pressure_kernel;
+        let deterministic = self.params.deterministic;
+
+        if n == 0 {
+            return SolverStats::default();
+        }
+
+        let neighbors = self.neighbor_lists();
+        let boundary_neighbors = self.boundary_neighbor_lists();
+        let boundary_gradients = self.boundary_gradients();
+        let boundary = &self.boundary;
+
+        let mut pressure = self.particles.take(PRESSURE);
+        let mut force = self.particles.take(FORCE);
+        let particles = &self.particles;
+        let (mass, density) = (particles.mass(), particles.density());
+        let (position, velocity) = (particles.position(), particles.velocity());
+        let delta = Self::pcisph_scaling_factor(&self.params, mass[0]);
+
+        // Accelerations due to all of the non-pressure forces.
+        let acceleration = parallel::map(n, |i| force[i] / density[i]);
+
+        let mut pressure_acceleration = vec![TV::zeros(); n];
+        let mut predicted_position = vec![TV::zeros(); n];
+        pressure.fill(0.);
+
+        let mut stats = SolverStats::default();
+        while stats.iterations < max_iterations {
+            parallel::for_each_mut(&mut predicted_position, |i, predicted| {
+                let v = velocity[i] + dt * (acceleration[i] + pressure_acceleration[i]);
+                *predicted = position[i] + dt * v;
+            });
+
+            let errors = parallel::map(n, |i| {
+                let x = predicted_position[i];
+                let fluid_density: T = neighbors[i]
+                    .iter()
+                    .map(|&j| {
+                        mass[j]
+                            * density_kernel
+                                .value(periodic.displacement(x, predicted_position[j]), h)
+                    })
+                    .sum();
+                let boundary_density: T = boundary_neighbors[i]
+                    .iter()
+                    .map(|&b| {
+                        boundary.psi[b]
+                            * density_kernel
+                                .value(periodic.displacement(x, boundary.position[b]), h)
+                    })
+                    .sum();
+                let density = fluid_density + boundary_density;
+
+                // Only compression is corrected, since particles at the free surface have
+                // incomplete neighborhoods.
+                (density - rest_density).max(0.)
+            });
+            parallel::for_each_mut(&mut pressure, |i, pressure| *pressure += delta * errors[i]);
+            let total_error = parallel::sum(&errors, deterministic);
+
+            parallel::for_each_mut(&mut pressure_acceleration, |i, acceleration| {
+                let x = position[i];
+                let pressure_i = pressure[i];
+                *acceleration = -neighbors[i]
+                    .iter()
+                    .filter(|&&j| j != i)
+                    .map(|&j| {
+                        let coeff = (pressure_i + pressure[j]) / (rest_density * rest_density);
+                        mass[j]
+                            * coeff
+                            * pressure_kernel.gradient(periodic.displacement(x, position[j]), h)
+                    })
+                    .sum::<TV>()
+                    - pressure_i / (rest_density * rest_density) * boundary_gradients[i];
+            });
+
+            stats.iterations += 1;
+            stats.density_error = total_error / (n.max(1) as T * rest_density);
+            trace!(stats.iterations, stats.density_error);
+
+            if stats.iterations >= MIN_ITERATIONS && stats.density_error <= tolerance {
+                break;
+            }
+        }
+
+        parallel::for_each_mut(&mut force, |i, force| {
+            *force += density[i] * pressure_acceleration[i];
+        });
+        self.particles.restore(PRESSURE, pressure);
+        self.particles.restore(FORCE, force);
+        self.apply_boundary_reaction();
+
+        stats
+    }
+
+    /// Computes the factor `delta` relating the density error to the pressure correction.
+    ///
+    /// This is derived for a particle with a full neighborhood, which is approximated using a
+    /// lattice of particles with the same volume `mass / rest_density` as the given particle.
+    fn pcisph_scaling_factor(params: &SphParamaters, mass: T) -> T {
+        let h = params.h;
+        let rest_density = params.rest_density;
+        let spacing = (mass / rest_density).powf(1. / DIM as T);
+
+        let extent = (h / spacing).ceil() as isize;
+        let range = Range::new(IV::from_element(-extent), IV::from_element(extent + 1));
+
+        let mut sum_gradient = TV::zeros();
+        let mut sum_gradient_squared = 0.;
+        for idx in RangeIterator::new(range) {
+            let r = -na::convert::<_, TV>(idx) * spacing;
+            let gradient = params.pressure_kernel.gradient(r, h);
+            sum_gradient += gradient;
+            sum_gradient_squared += gradient.norm_squared();
+        }
+
+        let beta = 2. * (params.delta_time * mass / rest_density).powi(2);
+        let denominator = beta * (sum_gradient.norm_squared() + sum_gradient_squared);
+        if denominator > 0. {
+            1. / denominator
+        } else {
+            0.
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::{PressureSolver, SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_pcisph_converges() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 4e-3,
+            gravity: TV::ith(1, -9.81),
+            pressure_solver: PressureSolver::Pcisph {
+                tolerance: 0.01,
+                max_iterations: 50,
+            },
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        for _ in 0..20 {
+            sim.advance_timestep();
+            let stats = sim.solver_stats.unwrap();
+            assert!(stats.iterations >= 3 && stats.iterations < 50);
+            assert!(stats.density_error <= 0.01);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+//! Heat transport and thermal buoyancy.
+//!
+//! Each particle carries a temperature in its [`TEMPERATURE`] attribute, which diffuses with the
+//! Laplacian of [Brookshaw 1985]:
+//!
+//! `dT_i/dt = alpha sum_j 4 m_j / (rho_i + rho_j) (T_i - T_j) (x_ij . grad W_ij) / (|x_ij|^2 + 0.01 h^2)`
+//!
+//! where `alpha` is the thermal diffusivity. The average density in the denominator makes the
+//! exchange between each pair of particles symmetric, so the total heat `sum_i m_i T_i` is
+//! conserved. Boundary particles inside a [`HeatSource`] are held at its temperature and exchange
+//! heat with the fluid in the same way, while the rest of the boundary is insulating.
+//!
+//! Temperature differences drive the flow through the Boussinesq approximation: the density is
+//! kept constant everywhere except in the gravity force, which becomes
+//! `rho_i g (1 - beta (T_i - T_0))` for the thermal expansion coefficient `beta`. Warm fluid then
+//! rises and cool fluid sinks, as in thermal convection.
+
+use super::particles::VISCOSITY;
+use super::SphSimulation;
+use crate::base::{Attribute, Range};
+use crate::math::*;
+use crate::util::parallel;
+use tracing::instrument;
+
+/// The temperature of each particle.
+pub const TEMPERATURE: Attribute<T> = Attribute::new("temperature");
+
+/// The parameters of heat transport. See [`super::SphParamaters::thermal`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ThermalParameters {
+    /// The thermal diffusivity `alpha`
+    pub diffusivity: T,
+    /// The temperature `T_0` at which the fluid has its rest density, and the initial temperature
+    /// of particles which were not given one
+    pub reference_temperature: T,
+    /// The thermal expansion coefficient `beta` of the Boussinesq buoyancy force
+    pub expansion_coefficient: T,
+    /// Regions of the boundary held at fixed temperatures
+    pub heat_sources: Vec<HeatSource>,
+    /// If set, the viscosity of each particle depends on its temperature
+    pub viscosity: Option<ThermalViscosity>,
+}
+
+impl Default for ThermalParameters {
+    fn default() -> Self {
+        Self {
+            diffusivity: 1e-4,
+            reference_temperature: 0.,
+            expansion_coefficient: 0.,
+            heat_sources: Vec::new(),
+            viscosity: None,
+        }
+    }
+}
+
+/// A region in which the boundary particles are held at a fixed temperature. This heats the fluid
+/// if it is warmer than the fluid, and acts as a heat sink if it is cooler.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HeatSource {
+    pub region: Range<TV>,
+    pub temperature: T,
+}
+
+impl HeatSource {
+    pub fn new(region: Range<TV>, temperature: T) -> Self {
+        Self {
+            region,
+            temperature,
+        }
+    }
+}
+
+/// How the viscosity depends on the temperature. The viscosity `mu` given by the phase, the
+/// rheology model, or [`super::SphParamaters::mu`] is that of the fluid at the reference
+/// temperature, or of the molten fluid.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ThermalViscosity {
+    /// `mu exp(-c (T - T_0))`, which decreases exponentially as the fluid heats up, like most
+    /// liquids.
+    Exponential {
+        /// The coefficient `c`
+        coefficient: T,
+    },
+    /// A smooth transition from `solid_viscosity` below the melting temperature to `mu` above it,
+    /// `mu + (mu_s - mu) / (1 + exp((T - T_m) / w))`, as for melting wax.
+    Melting {
+        /// The melting temperature `T_m`
+        melting_temperature: T,
+        /// The width `w` of the temperature range over which the material melts
+        width: T,
+        /// The viscosity `mu_s` of the solid material
+        solid_viscosity: T,
+    },
+}
+
+impl ThermalViscosity {
+    /// The viscosity at `temperature` of a fluid with viscosity `viscosity` at the reference
+    /// temperature `reference_temperature`, or in its molten state.
+    pub fn viscosity(&self, viscosity: T, temperature: T, reference_temperature: T) -> T {
+        match *self {
+            ThermalViscosity::Exponential { coefficient } => {
+                viscosity * (-coefficient * (temperature - reference_temperature)).exp()
+            }
+            ThermalViscosity::Melting {
+                melting_temperature,
+                width,
+                solid_viscosity,
+            } => {
+                let solid_fraction =
+                    1. / (1. + ((temperature - melting_temperature) / width).exp());
+                viscosity + (solid_viscosity - viscosity) * solid_fraction
+            }
+        }
+    }
+}
+
+impl SphSimulation {
+    /// The temperature of every particle, or `None` without heat transport.
+    pub fn temperatures(&self) -> Option<&[T]> {
+        self.params.thermal.as_ref()?;
+        // The attribute is added when the simulation is created.
+        Some(self.particles.data().get(TEMPERATURE).unwrap())
+    }
+
+    /// The temperature of every boundary particle which is inside a heat source.
+    fn boundary_temperatures(&self) -> Vec<Option<T>> {
+        let heat_sources = match &self.params.thermal {
+            Some(thermal) => &thermal.heat_sources,
+            None => return vec![None; self.boundary.position.len()],
+        };
+        self.boundary
+            .position
+            .iter()
+            .map(|&x| {
+                heat_sources
+                    .iter()
+                    .find(|source| source.region.contains(x))
+                    .map(|source| source.temperature)
+            })
+            .collect()
+    }
+
+    /// Advances the temperatures by one time step of heat diffusion. The densities must already
+    /// have been computed.
+    #[instrument(skip_all)]
+    pub(super) fn apply_heat_transfer(&mut self) {
+        let Some(thermal) = &self.params.thermal else {
+            return;
+        };
+        let alpha = thermal.diffusivity;
+        let boundary_temperature = self.boundary_temperatures();
+        let Some(temperature) = self.temperatures() else {
+            return;
+        };
+
+        let mass = self.particles.mass();
+        let density = self.particles.density();
+        let position = self.particles.position();
+        let boundary = &self.boundary;
+        let rest_density = self.params.rest_density;
+        let kernel = self.params.pressure_kernel;
+        let h = self.params.h;
+        let dt = self.params.delta_time;
+        let periodic = self.params.periodic_domain();
+        let brookshaw =
+            |r_ij: TV| r_ij.dot(&kernel.gradient(r_ij, h)) / (r_ij.norm_squared() + 0.01 * h * h);
+
+        let updated = parallel::map(self.params.num_particles, |i| {
+            let x = position[i];
+            let fluid: T = self
+                .get_neighbors(i)
+                .filter(|&j| j != i)
+                .map(|j| {
+                    let r_ij = periodic.displacement(x, position[j]);
+                    let volume = 2. * mass[j] / (density[i] + density[j]);
+                    2. * volume * (temperature[i] - temperature[j]) * brookshaw(r_ij)
+                })
+                .sum();
+            // The boundary particles have the volume `psi_b / rho_0`.
+            let boundary: T = self
+                .get_boundary_neighbors(i)
+                .filter_map(|b| {
+                    let temperature_b = boundary_temperature[b]?;
+                    let r_ib = periodic.displacement(x, boundary.position[b]);
+                    let volume = boundary.psi[b] / rest_density;
+                    Some(2. * volume * (temperature[i] - temperature_b) * brookshaw(r_ib))
+                })
+                .sum();
+            temperature[i] + dt * alpha * (fluid + boundary)
+        });
+
+        let temperature = self.particles.data_mut().get_mut(TEMPERATURE).unwrap();
+        temperature.copy_from_slice(&updated);
+    }
+
+    /// The factor `1 - beta (T_i - T_0)` by which the Boussinesq approximation scales the gravity
+    /// force on each particle, or `None` without heat transport.
+    pub(super) fn buoyancy_factors(&self) -> Option<Vec<T>> {
+        let thermal = self.params.thermal.as_ref()?;
+        let beta = thermal.expansion_coefficient;
+        let reference_temperature = thermal.reference_temperature;
+        let factors = self
+            .temperatures()?
+            .iter()
+            .map(|&temperature| 1. - beta * (temperature - reference_temperature))
+            .collect();
+        Some(factors)
+    }
+
+    /// Adjusts the viscosity of every particle for its temperature.
+    pub(super) fn apply_thermal_viscosities(&mut self) {
+        let Some(thermal) = &self.params.thermal else {
+            return;
+        };
+        let Some(model) = &thermal.viscosity else {
+            return;
+        };
+        let reference_temperature = thermal.reference_temperature;
+        let (viscosity, temperature) = self
+            .particles
+            .data_mut()
+            .get_many_mut((VISCOSITY, TEMPERATURE))
+            .unwrap();
+        for (viscosity, &temperature) in viscosity.iter_mut().zip(&*temperature) {
+            *viscosity = model.viscosity(*viscosity, temperature, reference_temperature);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sph::{SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_thermal_viscosity() {
+        let exponential = ThermalViscosity::Exponential { coefficient: 0.1 };
+        assert_eq!(exponential.viscosity(2., 20., 20.), 2.);
+        assert!((exponential.viscosity(2., 30., 20.) - 2. / 1f64.exp()).abs() < 1e-12);
+
+        let melting = ThermalViscosity::Melting {
+            melting_temperature: 60.,
+            width: 1.,
+            solid_viscosity: 1e4,
+        };
+        assert!((melting.viscosity(1., 20., 0.) - 1e4).abs() < 1e-9 * 1e4);
+        assert!((melting.viscosity(1., 100., 0.) - 1.).abs() < 1e-9);
+        assert!((melting.viscosity(1., 60., 0.) - 0.5 * (1e4 + 1.)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_diffusion_conserves_heat() {
+        // A box of fluid without gravity, hot in the lower half of the first axis and cold in the
+        // upper half, with insulating walls.
+        let size = 0.12;
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(size)),
+            delta_time: 1e-3,
+            gravity: TV::zeros(),
+            thermal: Some(ThermalParameters {
+                diffusivity: 2e-2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let half = TV::from_fn(|a, _| if a == 0 { 0.5 * size } else { size });
+        let mut sim = SphSimulationBuilder::new(params)
+            .temperature(1.)
+            .fill_box(Range::new(TV::zeros(), half))
+            .temperature(0.)
+            .fill_box(Range::new(TV::ith(0, 0.5 * size), TV::from_element(size)))
+            .boundary_walls()
+            .build()
+            .unwrap();
+
+        let heat = |sim: &SphSimulation| -> T {
+            let temperature = sim.temperatures().unwrap();
+            (0..sim.params.num_particles)
+                .map(|i| sim.particles.mass()[i] * temperature[i])
+                .sum()
+        };
+        let initial = heat(&sim);
+
+        for _ in 0..20 {
+            sim.fill_cells();
+            sim.calculate_densities();
+            sim.apply_heat_transfer();
+        }
+
+        assert!((heat(&sim) - initial).abs() < 1e-9 * initial);
+        // The particles next to the interface have exchanged heat.
+        let temperature = sim.temperatures().unwrap();
+        for (x, &t) in sim.particles.position().iter().zip(temperature) {
+            assert!((0. ..=1.).contains(&t));
+            if (x[0] - 0.5 * size).abs() < 0.02 {
+                assert!(t > 0.1 && t < 0.9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_heat_source_warms_fluid() {
+        let size = 0.12;
+        let floor = Range::new(
+            TV::from_element(-1.),
+            TV::from_fn(|a, _| if a == 1 { 0. } else { 1. }),
+        );
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(size)),
+            delta_time: 1e-3,
+            gravity: TV::zeros(),
+            thermal: Some(ThermalParameters {
+                diffusivity: 2e-2,
+                heat_sources: vec![HeatSource::new(floor, 1.)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params.clone())
+            .fill_box(params.domain)
+            .boundary_walls()
+            .build()
+            .unwrap();
+
+        for _ in 0..20 {
+            sim.fill_cells();
+            sim.calculate_densities();
+            sim.apply_heat_transfer();
+        }
+
+        // Only the particles next to the floor are heated.
+        let temperature = sim.temperatures().unwrap();
+        for (x, &t) in sim.particles.position().iter().zip(temperature) {
+            assert!((0. ..1.).contains(&t));
+            if x[1] < 0.02 {
+                assert!(t > 0.3);
+            } else if x[1] > 0.06 {
+                assert!(t < 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_warm_fluid_rises() {
+        // A column of cold fluid, with warm fluid at the bottom center.
+        let size = TV::from_fn(|a, _| if a == 1 { 0.2 } else { 0.12 });
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), size),
+            delta_time: 4e-3,
+            gravity: TV::ith(1, -9.81),
+            mu: 0.5,
+            thermal: Some(ThermalParameters {
+                diffusivity: 1e-6,
+                expansion_coefficient: 0.5,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), size - TV::ith(1, 0.08)))
+            .boundary_walls()
+            .build()
+            .unwrap();
+        let center = 0.5 * size;
+        let warm: Vec<usize> = (0..sim.params.num_particles)
+            .filter(|&i| {
+                let x = sim.particles.position()[i];
+                x[1] < 0.06 && (x - center).remove_row(1).norm() < 0.03
+            })
+            .collect();
+        let temperature = sim.particles.data_mut().get_mut(TEMPERATURE).unwrap();
+        for &i in &warm {
+            temperature[i] = 1.;
+        }
+        let mean_height = |sim: &SphSimulation| {
+            warm.iter()
+                .map(|&i| sim.particles.position()[i][1])
+                .sum::<T>()
+                / warm.len() as T
+        };
+
+        let initial = mean_height(&sim);
+        for _ in 0..60 {
+            sim.advance_timestep();
+        }
+
+        // Without buoyancy, the fluid only moves by about 0.005 as it settles.
+        assert!(mean_height(&sim) > initial + 0.015);
+    }
+}
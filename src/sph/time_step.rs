@@ -0,0 +1,212 @@
+//! Adaptive time stepping.
+//!
+//! The largest stable time step depends on the state of the simulation, so it is chosen in each
+//! step as the minimum of three limits, following e.g. [Monaghan 1992] and [Ihmsen et al. 2014]:
+//!
+//! * the CFL condition `dt <= cfl * h / v_max`, so that no particle moves further than a fraction
+//!   of the smoothing radius. For the Tait equation of state, the speed of sound is added to
+//!   `v_max`, as is the speed of sound of the artificial viscosity,
+//! * the force criterion `dt <= force_factor * sqrt(h / a_max)`,
+//! * the viscous diffusion limit `dt <= viscous_factor * h^2 / nu`, where `nu = mu / rho_0`, for
+//!   the explicit Laplacian viscosity. With a rheology model, multiple phases, or
+//!   temperature-dependent viscosity, the largest particle viscosity is used. The same limit
+//!   applies to the thermal diffusivity with heat transport.
+
+use super::{EquationOfState, SphSimulation, ViscosityModel};
+use crate::math::*;
+use tracing::{debug, instrument};
+
+/// The parameters used to choose the time step in each step. See
+/// [`super::SphParamaters::adaptive_time_step`].
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AdaptiveTimeStep {
+    /// The CFL number, the fraction of `h` a particle may move in one step
+    pub cfl: T,
+    /// The factor of the force criterion
+    pub force_factor: T,
+    /// The factor of the viscous diffusion limit
+    pub viscous_factor: T,
+    /// The smallest allowed time step
+    pub min_dt: T,
+    /// The largest allowed time step
+    pub max_dt: T,
+}
+
+impl Default for AdaptiveTimeStep {
+    fn default() -> Self {
+        Self {
+            cfl: 0.4,
+            force_factor: 0.25,
+            viscous_factor: 0.125,
+            min_dt: 1e-5,
+            max_dt: 0.01,
+        }
+    }
+}
+
+impl SphSimulation {
+    /// Computes the time step from the stability limits, clamped to `min_dt` and `max_dt`.
+    ///
+    /// The accelerations are taken from the forces of the previous step, along with gravity, since
+    /// the forces of the next step depend on the time step.
+    pub fn stable_time_step(&self, adaptive: &AdaptiveTimeStep) -> T {
+        let h = self.params.h;
+        let particles = &self.particles;
+
+        let max_speed = particles.velocity().iter().map(TV::norm).fold(0., T::max);
+        let pressure_signal_speed = match self.params.equation_of_state {
+            EquationOfState::Tait { speed_of_sound, .. } => speed_of_sound,
+            EquationOfState::IdealGas => 0.,
+        };
+        let (viscosity_signal_speed, kinematic_viscosity) = match self.params.viscosity {
+            ViscosityModel::Laplacian => (0., self.max_viscosity() / self.params.rest_density),
+            ViscosityModel::Artificial { speed_of_sound, .. } => (speed_of_sound, 0.),
+            ViscosityModel::Implicit { .. } => (0., 0.),
+        };
+        let thermal_diffusivity = self
+            .params
+            .thermal
+            .as_ref()
+            .map_or(0., |thermal| thermal.diffusivity);
+        let signal_speed = max_speed + pressure_signal_speed.max(viscosity_signal_speed);
+
+        let max_acceleration = particles
+            .force()
+            .iter()
+            .zip(particles.density())
+            .filter(|(_, &density)| density > 0.)
+            .map(|(force, density)| (force / *density).norm())
+            .fold(self.params.gravity.norm(), T::max);
+
+        let limits = [
+            adaptive.cfl * h / signal_speed,
+            adaptive.force_factor * (h / max_acceleration).sqrt(),
+            adaptive.viscous_factor * h * h / kinematic_viscosity,
+            adaptive.viscous_factor * h * h / thermal_diffusivity,
+        ];
+
+        // Limits with zero speed, acceleration or viscosity are infinite, and do not apply.
+        let dt = limits.iter().copied().fold(T::INFINITY, T::min);
+        dt.max(adaptive.min_dt).min(adaptive.max_dt)
+    }
+
+    /// The largest viscosity of any particle, which is `mu` unless the particles have their own
+    /// viscosities. The viscosities are taken from the previous step.
+    fn max_viscosity(&self) -> T {
+        let thermal_viscosity = self
+            .params
+            .thermal
+            .as_ref()
+            .is_some_and(|thermal| thermal.viscosity.is_some());
+        if self.params.rheology.is_some() || self.is_multiphase() || thermal_viscosity {
+            self.particles.viscosity().iter().copied().fold(0., T::max)
+        } else {
+            self.params.mu
+        }
+    }
+
+    /// Advances the simulation to exactly `time`, taking as many steps as needed. With an
+    /// adaptive time step, the last steps are shortened to land on `time`; otherwise, the last
+    /// step is shortened if `time` is not a multiple of `delta_time`.
+    ///
+    /// This is useful for writing output frames at regular intervals.
+    #[instrument(skip_all)]
+    pub fn advance_to(&mut self, time: T) {
+        let fixed_dt = self.params.delta_time;
+        while self.time < time {
+            let dt = match &self.params.adaptive_time_step {
+                Some(adaptive) => self.stable_time_step(adaptive),
+                None => fixed_dt,
+            };
+
+            let remaining = time - self.time;
+            let last = remaining <= dt;
+            // Split the remainder in half rather than leaving a tiny final step, which would be
+            // wasted, or unstable for solvers that divide by dt.
+            self.params.delta_time = if last {
+                remaining
+            } else if remaining < 2. * dt {
+                0.5 * remaining
+            } else {
+                dt
+            };
+
+            self.step();
+            if last {
+                // `self.time + remaining` may round to just below `time`, which must not cause
+                // another, tiny step.
+                self.time = time;
+            }
+        }
+
+        if self.params.adaptive_time_step.is_none() {
+            self.params.delta_time = fixed_dt;
+        }
+        debug!(steps = self.dt_history.len(), self.time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdaptiveTimeStep;
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::{SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_adaptive_time_step() {
+        let adaptive = AdaptiveTimeStep::default();
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            gravity: TV::ith(1, -9.81),
+            adaptive_time_step: Some(adaptive.clone()),
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .velocity(TV::ith(0, 2.))
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        // The CFL condition limits the time step for fast particles.
+        let h = sim.params.h;
+        let dt = sim.stable_time_step(&adaptive);
+        assert!(dt <= adaptive.cfl * h / 2. + 1e-12);
+        assert!(dt >= adaptive.min_dt);
+
+        let frame = 1. / 60.;
+        for i in 1..=3 {
+            sim.advance_to(i as T * frame);
+            assert_eq!(sim.time, i as T * frame);
+        }
+
+        let history = &sim.dt_history;
+        assert!(history.len() > 3);
+        assert!((history.iter().sum::<T>() - 3. * frame).abs() < 1e-12);
+        assert!(history.iter().all(|&dt| dt > 0. && dt <= adaptive.max_dt));
+    }
+
+    #[test]
+    fn test_advance_to_unrepresentable_time() {
+        let dt = 1. / 30.;
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            gravity: TV::ith(1, -9.81),
+            delta_time: dt,
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        // Neither 0.1 nor 1/30 is representable, so the sum of the steps is not exactly 0.1.
+        sim.advance_to(0.1);
+        assert_eq!(sim.time, 0.1);
+        assert_eq!(sim.params.delta_time, dt);
+
+        let last = *sim.dt_history.last().unwrap();
+        assert!(last > 0.4 * dt, "the last step is {last}");
+        assert!((sim.dt_history.iter().sum::<T>() - 0.1).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,473 @@
+//! Viscosity forces and XSPH velocity smoothing.
+//!
+//! The viscosity forces are chosen with [`ViscosityModel`]. The Laplacian model of [Müller et al.
+//! 2003] is simple and smooth, but it does not conserve angular momentum. The artificial viscosity
+//! of [Monaghan 1997] only acts between approaching particles, along the line between them, so it
+//! conserves angular momentum and dissipates little energy outside of shocks. For very viscous
+//! fluids, the implicit viscosity of [Weiler et al. 2018] remains stable with large time steps.
+//!
+//! XSPH [Monaghan 1989] blends each velocity with the velocities of its neighbors,
+//! `v_i += c sum_j m_j / rho_j (v_j - v_i) W_ij`, which makes the particle motion more orderly
+//! without adding a force.
+
+use super::particles::{FORCE, VELOCITY};
+use super::{PressureSolver, SphSimulation, ViscosityModel};
+use crate::math::*;
+use crate::neighbors::NeighborLists;
+use crate::util::conjugate_gradient::conjugate_gradient;
+use crate::util::parallel;
+use tracing::{debug, instrument};
+
+impl SphSimulation {
+    /// Adds the viscosity force densities of the configured [`ViscosityModel`]. The other
+    /// non-pressure forces must already have been applied, since the implicit viscosity acts on the
+    /// velocities they predict.
+    pub(super) fn apply_viscosity_force(&mut self) {
+        if !matches!(self.params.viscosity, ViscosityModel::Artificial { .. }) {
+            self.calculate_viscosities();
+        }
+
+        match self.params.viscosity {
+            ViscosityModel::Laplacian => self.apply_laplacian_viscosity(),
+            ViscosityModel::Artificial {
+                alpha,
+                beta,
+                speed_of_sound,
+            } => self.apply_artificial_viscosity(alpha, beta, speed_of_sound),
+            ViscosityModel::Implicit {
+                tolerance,
+                max_iterations,
+            } => self.apply_implicit_viscosity(tolerance, max_iterations),
+        }
+    }
+
+    #[instrument(skip_all)]
+    fn apply_laplacian_viscosity(&mut self) {
+        let mut force = self.particles.take(FORCE);
+        let mass = self.particles.mass();
+        let density = self.particles.density();
+        let position = self.particles.position();
+        let velocity = self.particles.velocity();
+        let kernel = self.params.viscosity_kernel;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+
+        parallel::for_each_mut(&mut force, |i, force| {
+            let neighbors = self.get_neighbors(i);
+
+            let force_viscosity = neighbors
+                .map(|j| {
+                    if i == j {
+                        return TV::zeros();
+                    }
+                    let vdiff = velocity[j] - velocity[i];
+                    let r_ij = periodic.displacement(position[i], position[j]);
+
+                    self.pair_viscosity(i, j) * mass[j] * vdiff / density[j]
+                        * kernel.laplacian(r_ij, h)
+                })
+                .sum::<TV>();
+
+            *force += force_viscosity;
+        });
+        self.particles.restore(FORCE, force);
+    }
+
+    /// Adds the artificial viscosity force densities `-rho_i sum_j m_j Pi_ij grad W_ij`. See
+    /// [`ViscosityModel::Artificial`].
+    #[instrument(skip_all)]
+    fn apply_artificial_viscosity(&mut self, alpha: T, beta: T, speed_of_sound: T) {
+        let mut force = self.particles.take(FORCE);
+        let mass = self.particles.mass();
+        let density = self.particles.density();
+        let position = self.particles.position();
+        let velocity = self.particles.velocity();
+        let kernel = self.params.pressure_kernel;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+
+        parallel::for_each_mut(&mut force, |i, force| {
+            let force_viscosity: TV = self
+                .get_neighbors(i)
+                .filter(|&j| j != i)
+                .filter_map(|j| {
+                    let r_ij = periodic.displacement(position[i], position[j]);
+                    let direction = r_ij.try_normalize(0.)?;
+                    // The relative velocity along the line between the particles, which is
+                    // negative when they approach each other.
+                    let w_ij = (velocity[i] - velocity[j]).dot(&direction);
+                    if w_ij >= 0. {
+                        return None;
+                    }
+
+                    let signal_velocity = 2. * speed_of_sound - beta * w_ij;
+                    let average_density = 0.5 * (density[i] + density[j]);
+                    let pi_ij = -alpha * signal_velocity * w_ij / (2. * average_density);
+                    Some(-mass[j] * pi_ij * kernel.gradient(r_ij, h))
+                })
+                .sum();
+
+            *force += density[i] * force_viscosity;
+        });
+        self.particles.restore(FORCE, force);
+    }
+
+    /// Solves for the velocities after an implicit viscosity step, and adds the force densities
+    /// `rho_i (v_i' - v*_i) / dt` which produce them. See [`ViscosityModel::Implicit`].
+    ///
+    /// As in [Weiler et al. 2018], the system is solved starting from the predicted velocities
+    /// `v*_i = v_i + dt f_i / rho_i` of the forces applied so far, so that the viscosity also
+    /// couples the accelerations of gravity and surface tension.
+    ///
+    /// Each row of the system is multiplied by `m_i`, which makes it symmetric positive definite:
+    /// `m_i v_i - dt 2 (d + 2) sum_j mu_ij m_i m_j / (rho_i rho_j) (v_ij . x_ij) / (|x_ij|^2 +
+    /// 0.01 h^2) grad W_ij = m_i v*_i`, where `mu_ij` is the viscosity of the pair.
+    #[instrument(skip_all)]
+    fn apply_implicit_viscosity(&mut self, tolerance: T, max_iterations: usize) {
+        let n = self.params.num_particles;
+        let dt = self.params.delta_time;
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let kernel = self.params.pressure_kernel;
+        let scale = dt * 2. * (DIM + 2) as T;
+        if n == 0 || self.particles.viscosity().iter().all(|&mu| mu == 0.) {
+            return;
+        }
+
+        let neighbors = self.neighbor_lists();
+        let particles = &self.particles;
+        let (mass, density, position) =
+            (particles.mass(), particles.density(), particles.position());
+
+        // The coefficient `dt mu_ij 2 (d + 2) m_i m_j / (rho_i rho_j) / (|x_ij|^2 + 0.01 h^2)` and
+        // the kernel gradient of every neighbor pair, which stay fixed during the solve.
+        let pairs: Vec<Vec<(usize, T, TV, TV)>> = parallel::map(n, |i| {
+            neighbors[i]
+                .iter()
+                .filter(|&&j| j != i)
+                .map(|&j| {
+                    let r_ij = periodic.displacement(position[i], position[j]);
+                    let coeff = scale * self.pair_viscosity(i, j) * mass[i] * mass[j]
+                        / (density[i] * density[j])
+                        / (r_ij.norm_squared() + 0.01 * h * h);
+                    (j, coeff, r_ij, kernel.gradient(r_ij, h))
+                })
+                .collect()
+        });
+
+        let apply = |v: &[TV]| -> Vec<TV> {
+            parallel::map(n, |i| {
+                let laplacian: TV = pairs[i]
+                    .iter()
+                    .map(|&(j, coeff, r_ij, grad)| coeff * (v[i] - v[j]).dot(&r_ij) * grad)
+                    .sum();
+                mass[i] * v[i] - laplacian
+            })
+        };
+
+        // The trace of each diagonal block, divided by `DIM`.
+        let inverse_diagonal: Vec<T> = parallel::map(n, |i| {
+            let laplacian: T = pairs[i]
+                .iter()
+                .map(|&(_, coeff, r_ij, grad)| -coeff * r_ij.dot(&grad))
+                .sum();
+            1. / (mass[i] + laplacian / DIM as T)
+        });
+
+        let (velocity, force) = (particles.velocity(), particles.force());
+        let predicted: Vec<TV> = parallel::map(n, |i| velocity[i] + dt * force[i] / density[i]);
+        let b: Vec<TV> = (0..n).map(|i| mass[i] * predicted[i]).collect();
+        let mut solved = predicted.clone();
+        let stats = conjugate_gradient(
+            apply,
+            &b,
+            &mut solved,
+            &inverse_diagonal,
+            tolerance,
+            max_iterations,
+        );
+        debug!(stats.iterations, stats.residual);
+
+        let viscosity_force: Vec<TV> =
+            parallel::map(n, |i| density[i] * (solved[i] - predicted[i]) / dt);
+        parallel::for_each_mut(self.particles.force_mut(), |i, force| {
+            *force += viscosity_force[i];
+        });
+    }
+
+    /// Applies XSPH with the coefficient `params.xsph` at the end of a step, for every solver but
+    /// PBF, which applies its own XSPH. The neighbors are found at the new positions.
+    #[instrument(skip_all)]
+    pub(super) fn apply_step_xsph(&mut self) {
+        let c = self.params.xsph;
+        if c == 0. || matches!(self.params.pressure_solver, PressureSolver::Pbf(_)) {
+            return;
+        }
+
+        self.fill_cells();
+        let neighbors = self.neighbor_lists();
+        self.apply_xsph(c, &neighbors);
+    }
+
+    /// Applies XSPH viscosity, which blends each velocity with the velocities of its neighbors.
+    pub(super) fn apply_xsph(&mut self, c: T, neighbors: &NeighborLists) {
+        if c == 0. {
+            return;
+        }
+
+        let h = self.params.h;
+        let periodic = self.params.periodic_domain();
+        let kernel = self.params.density_kernel;
+
+        let particles = &mut self.particles;
+        let mass = particles.mass();
+        let density = particles.density();
+        let position = particles.position();
+        let velocity = particles.velocity();
+
+        let smoothed: Vec<TV> = (0..self.params.num_particles)
+            .map(|i| {
+                velocity[i]
+                    + c * neighbors[i]
+                        .iter()
+                        .map(|&j| {
+                            let w =
+                                kernel.value(periodic.displacement(position[i], position[j]), h);
+                            mass[j] / density[j] * (velocity[j] - velocity[i]) * w
+                        })
+                        .sum::<TV>()
+            })
+            .collect();
+
+        particles.restore(VELOCITY, smoothed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::particles::{DENSITY, FORCE, POSITION, VELOCITY};
+    use crate::sph::{
+        EquationOfState, PressureSolver, SphParamaters, SphSimulation, SphSimulationBuilder,
+        ViscosityModel,
+    };
+    use crate::util::integrators::Integrator;
+
+    /// Creates a square (or cube) of fluid without gravity, spinning about its center.
+    fn spinning_blob(viscosity: ViscosityModel) -> SphSimulation {
+        let center = TV::from_element(0.25);
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 1e-3,
+            gravity: TV::zeros(),
+            pressure_solver: PressureSolver::StateEquation,
+            equation_of_state: EquationOfState::tait(10.),
+            integrator: Integrator::SymplecticEuler,
+            viscosity,
+            ..Default::default()
+        };
+        let blob = Range::new(
+            center - TV::from_element(0.05),
+            center + TV::from_element(0.05),
+        );
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(blob)
+            .build()
+            .unwrap();
+
+        // Rotation in the plane of the first two axes.
+        let data = sim.particles.data_mut();
+        for (x, v) in data.iter_mut((POSITION, VELOCITY)).unwrap() {
+            let r = *x - center;
+            *v = TV::from_fn(|a, _| match a {
+                0 => -r[1],
+                1 => r[0],
+                _ => 0.,
+            });
+        }
+        sim
+    }
+
+    /// The angular momentum `sum_i m_i (x_i v_i^T - v_i x_i^T)`, which works in any dimension.
+    fn angular_momentum(sim: &SphSimulation) -> na::SMatrix<T, DIM, DIM> {
+        let particles = &sim.particles;
+        (0..sim.params.num_particles)
+            .map(|i| {
+                let (x, v) = (particles.position()[i], particles.velocity()[i]);
+                particles.mass()[i] * (x * v.transpose() - v * x.transpose())
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_artificial_viscosity_conserves_angular_momentum() {
+        let mut laplacian = spinning_blob(ViscosityModel::Laplacian);
+        let mut artificial = spinning_blob(ViscosityModel::Artificial {
+            alpha: 0.5,
+            beta: 1.,
+            speed_of_sound: 10.,
+        });
+        let initial = angular_momentum(&artificial);
+
+        for _ in 0..50 {
+            laplacian.advance_timestep();
+            artificial.advance_timestep();
+        }
+
+        let error = |sim: &SphSimulation| (angular_momentum(sim) - initial).norm() / initial.norm();
+        assert!(error(&artificial) < 1e-9);
+        assert!(error(&laplacian) > 1e-3);
+    }
+
+    #[test]
+    fn test_xsph_smooths_velocities() {
+        // The spread of the velocities of a box of fluid, after one step starting with alternating
+        // velocities.
+        let spread_after_step = |xsph: T| {
+            let params = SphParamaters {
+                domain: Range::new(TV::zeros(), TV::from_element(0.2)),
+                gravity: TV::zeros(),
+                mu: 0.,
+                xsph,
+                ..Default::default()
+            };
+            let mut sim = SphSimulationBuilder::new(params.clone())
+                .fill_box(params.domain)
+                .build()
+                .unwrap();
+            for (i, v) in sim.particles.velocity_mut().iter_mut().enumerate() {
+                *v = TV::ith(0, if i % 2 == 0 { 0.1 } else { -0.1 });
+            }
+
+            sim.advance_timestep();
+            let velocity = sim.particles.velocity();
+            let mean = velocity.iter().sum::<TV>() / velocity.len() as T;
+            velocity
+                .iter()
+                .map(|v| (v - mean).norm_squared())
+                .sum::<T>()
+        };
+
+        assert!(spread_after_step(0.5) < 0.75 * spread_after_step(0.));
+    }
+
+    #[test]
+    fn test_implicit_viscosity_is_stable() {
+        // A box of fluid with a viscosity far beyond the explicit
+        // stability limit `dt <= 0.125 h^2 rho_0 / mu`, which is about 2e-6 here.
+        let run = |viscosity: ViscosityModel| {
+            let params = SphParamaters {
+                domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+                delta_time: 4e-3,
+                gravity: TV::zeros(),
+                mu: 1e5,
+                viscosity,
+                ..Default::default()
+            };
+            let blob = Range::new(TV::from_element(0.2), TV::from_element(0.3));
+            let mut sim = SphSimulationBuilder::new(params)
+                .fill_box(blob)
+                .build()
+                .unwrap();
+            // The two halves of the box move towards each other, without angular momentum, which
+            // the viscosity would conserve.
+            let data = sim.particles.data_mut();
+            for (x, v) in data.iter_mut((POSITION, VELOCITY)).unwrap() {
+                *v = TV::ith(0, if x[0] < 0.25 { 0.1 } else { -0.1 });
+            }
+
+            let momentum = |sim: &SphSimulation| -> TV {
+                let particles = &sim.particles;
+                particles
+                    .mass()
+                    .iter()
+                    .zip(particles.velocity())
+                    .map(|(m, v)| *m * v)
+                    .sum()
+            };
+            // The kinetic energy relative to the center of mass.
+            let kinetic_energy = |sim: &SphSimulation| -> T {
+                let particles = &sim.particles;
+                let mean = momentum(sim) / particles.mass().iter().sum::<T>();
+                (particles.mass().iter().zip(particles.velocity()))
+                    .map(|(m, v)| 0.5 * m * (v - mean).norm_squared())
+                    .sum()
+            };
+            let (initial_momentum, initial_energy) = (momentum(&sim), kinetic_energy(&sim));
+            for _ in 0..10 {
+                sim.advance_timestep();
+            }
+            (
+                kinetic_energy(&sim) / initial_energy,
+                (momentum(&sim) - initial_momentum).norm(),
+            )
+        };
+
+        let (energy, momentum_error) = run(ViscosityModel::Implicit {
+            tolerance: 1e-8,
+            max_iterations: 200,
+        });
+        assert!(energy < 1e-2);
+        assert!(momentum_error < 1e-6);
+
+        let (energy, _) = run(ViscosityModel::Laplacian);
+        // The explicit viscosity is unstable with this time step.
+        assert!(energy.is_nan() || energy > 1.);
+    }
+
+    #[test]
+    fn test_implicit_viscosity_couples_forces() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 4e-3,
+            gravity: TV::zeros(),
+            mu: 1e5,
+            viscosity: ViscosityModel::Implicit {
+                tolerance: 1e-8,
+                max_iterations: 200,
+            },
+            ..Default::default()
+        };
+        let blob = Range::new(TV::from_element(0.2), TV::from_element(0.3));
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(blob)
+            .build()
+            .unwrap();
+        sim.fill_cells();
+        sim.calculate_densities();
+
+        // An acceleration of one half of the box, such as gravity scaled by temperature, which the
+        // viscosity should spread over the whole box.
+        let dt = sim.params.delta_time;
+        let data = sim.particles.data_mut();
+        let (position, density, force) = data.get_many_mut((POSITION, DENSITY, FORCE)).unwrap();
+        for i in 0..position.len() {
+            if position[i][0] < 0.25 {
+                force[i] = density[i] * TV::ith(1, -10.);
+            }
+        }
+        sim.apply_viscosity_force();
+
+        // The viscosity removes the relative velocities along the lines between neighbors, so the
+        // box moves as a rigid body, which also rotates since the force is off-center. Without the
+        // viscosity, the velocities of the halves would differ by `0.04`.
+        let particles = &sim.particles;
+        let (position, velocity) = (particles.position(), particles.velocity());
+        let new_velocity: Vec<TV> = (0..particles.len())
+            .map(|i| velocity[i] + dt * particles.force()[i] / particles.density()[i])
+            .collect();
+        let h = sim.params.h;
+        for i in 0..particles.len() {
+            for j in 0..i {
+                let r_ij = position[i] - position[j];
+                if r_ij.norm() < h {
+                    let strain = (new_velocity[i] - new_velocity[j]).dot(&r_ij.normalize());
+                    assert!(strain.abs() < 1e-2 * 0.04, "{strain}");
+                }
+            }
+        }
+        let mean = new_velocity.iter().sum::<TV>() / new_velocity.len() as T;
+        assert!((mean - TV::ith(1, -0.02)).norm() < 1e-9);
+    }
+}
@@ -0,0 +1,113 @@
+//! A matrix-free preconditioned conjugate gradient solver for symmetric positive definite systems
+//! whose unknowns are one vector per particle.
+//!
+//! The matrix is never formed. Instead, the caller provides a function which applies it to a
+//! vector, which for SPH is a sum over the neighbors of each particle. See Golub and Van Loan,
+//! Matrix Computations, Ch. 11.
+
+use crate::math::*;
+
+/// Statistics reported by [`conjugate_gradient`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CgStats {
+    /// The number of iterations performed
+    pub iterations: usize,
+    /// The final residual `|b - A x|`, relative to `|b|`
+    pub residual: T,
+}
+
+fn dot(a: &[TV], b: &[TV]) -> T {
+    a.iter().zip(b).map(|(a, b)| a.dot(b)).sum()
+}
+
+/// Solves `A x = b` with the Jacobi preconditioned conjugate gradient method, starting from the
+/// initial guess in `x`. `apply` computes `A x`, and `inverse_diagonal` holds the inverse of an
+/// approximation of the diagonal of `A` for each particle.
+///
+/// Iterations stop once the residual relative to `|b|` is below `tolerance`, or after
+/// `max_iterations`.
+pub fn conjugate_gradient<F>(
+    apply: F,
+    b: &[TV],
+    x: &mut [TV],
+    inverse_diagonal: &[T],
+    tolerance: T,
+    max_iterations: usize,
+) -> CgStats
+where
+    F: Fn(&[TV]) -> Vec<TV>,
+{
+    let norm_b = dot(b, b).sqrt();
+    if norm_b == 0. {
+        x.fill(TV::zeros());
+        return CgStats::default();
+    }
+
+    let ax = apply(x);
+    let mut r: Vec<TV> = b.iter().zip(&ax).map(|(b, ax)| b - ax).collect();
+    let mut z: Vec<TV> = r
+        .iter()
+        .zip(inverse_diagonal)
+        .map(|(r, &d)| d * r)
+        .collect();
+    let mut p = z.clone();
+    let mut rz = dot(&r, &z);
+
+    let mut stats = CgStats {
+        iterations: 0,
+        residual: dot(&r, &r).sqrt() / norm_b,
+    };
+    while stats.residual > tolerance && stats.iterations < max_iterations {
+        let ap = apply(&p);
+        let alpha = rz / dot(&p, &ap);
+        for i in 0..x.len() {
+            x[i] += alpha * p[i];
+            r[i] -= alpha * ap[i];
+            z[i] = inverse_diagonal[i] * r[i];
+        }
+
+        let rz_next = dot(&r, &z);
+        let beta = rz_next / rz;
+        rz = rz_next;
+        for (p, z) in p.iter_mut().zip(&z) {
+            *p = z + beta * *p;
+        }
+
+        stats.iterations += 1;
+        stats.residual = dot(&r, &r).sqrt() / norm_b;
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conjugate_gradient() {
+        // A 1d Laplacian plus a diagonal term, applied to each component separately.
+        let n = 20;
+        let apply = |x: &[TV]| -> Vec<TV> {
+            (0..n)
+                .map(|i| {
+                    let left = if i > 0 { x[i - 1] } else { TV::zeros() };
+                    let right = if i + 1 < n { x[i + 1] } else { TV::zeros() };
+                    (3. + i as T) * x[i] - left - right
+                })
+                .collect()
+        };
+        let expected: Vec<TV> = (0..n)
+            .map(|i| TV::from_element(i as T).map(T::sin))
+            .collect();
+        let b = apply(&expected);
+        let inverse_diagonal: Vec<T> = (0..n).map(|i| 1. / (3. + i as T)).collect();
+
+        let mut x = vec![TV::zeros(); n];
+        let stats = conjugate_gradient(apply, &b, &mut x, &inverse_diagonal, 1e-12, 100);
+        assert!(stats.residual <= 1e-12);
+        assert!(stats.iterations <= n);
+        for (x, expected) in x.iter().zip(&expected) {
+            assert!((x - expected).norm() < 1e-9);
+        }
+    }
+}
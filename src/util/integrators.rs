@@ -0,0 +1,154 @@
+//! Time integrators for particle systems, where the state of each particle is its position and
+//! velocity, and the acceleration is a function of the whole state.
+//!
+//! The symplectic schemes (symplectic Euler, leapfrog and velocity Verlet) do not conserve the
+//! energy exactly, but the energy error stays bounded over long simulations, rather than drifting.
+//! RK4 is much more accurate over short times, but slowly dissipates energy. See Hairer, E.,
+//! Lubich, C., & Wanner, G. (2003). Geometric numerical integration illustrated by the
+//! Störmer–Verlet method. Acta numerica, 12, 399-450.
+use crate::math::*;
+
+/// The scheme used to advance particle positions and velocities over a time step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Integrator {
+    /// `v += dt * a(x, v)`, then `x += dt * v`. First order, with one acceleration evaluation per
+    /// step.
+    #[default]
+    SymplecticEuler,
+    /// Kick-drift-kick leapfrog: a half step of the velocity, a full step of the position using the
+    /// half-step velocity, and another half step of the velocity. Second order, with two
+    /// acceleration evaluations per step.
+    Leapfrog,
+    /// Velocity Verlet: the position is advanced using the current acceleration, and the velocity
+    /// using the average of the current and new accelerations. The new acceleration is evaluated
+    /// with the velocity predicted by an Euler step. This is identical to leapfrog when the
+    /// acceleration does not depend on the velocity.
+    VelocityVerlet,
+    /// The classical fourth order Runge-Kutta method, with four acceleration evaluations per step.
+    Rk4,
+}
+
+impl Integrator {
+    /// Advances `position` and `velocity` by `dt`.
+    ///
+    /// `acceleration` must contain the accelerations at the current state, and `evaluate` computes
+    /// the accelerations at any other state.
+    pub fn step<F>(
+        &self,
+        position: &mut [TV],
+        velocity: &mut [TV],
+        acceleration: &[TV],
+        dt: T,
+        mut evaluate: F,
+    ) where
+        F: FnMut(&[TV], &[TV]) -> Vec<TV>,
+    {
+        let n = position.len();
+        match self {
+            Integrator::SymplecticEuler => {
+                for i in 0..n {
+                    velocity[i] += dt * acceleration[i];
+                    position[i] += dt * velocity[i];
+                }
+            }
+            Integrator::Leapfrog => {
+                for i in 0..n {
+                    velocity[i] += 0.5 * dt * acceleration[i];
+                    position[i] += dt * velocity[i];
+                }
+                let new_acceleration = evaluate(position, velocity);
+                for i in 0..n {
+                    velocity[i] += 0.5 * dt * new_acceleration[i];
+                }
+            }
+            Integrator::VelocityVerlet => {
+                let mut predicted_velocity = velocity.to_vec();
+                for i in 0..n {
+                    position[i] += dt * velocity[i] + 0.5 * dt * dt * acceleration[i];
+                    predicted_velocity[i] += dt * acceleration[i];
+                }
+                let new_acceleration = evaluate(position, &predicted_velocity);
+                for i in 0..n {
+                    velocity[i] += 0.5 * dt * (acceleration[i] + new_acceleration[i]);
+                }
+            }
+            Integrator::Rk4 => {
+                // Each stage gives the derivative of the position (a velocity) and of the velocity
+                // (an acceleration).
+                let stage = |x0: &[TV], v0: &[TV], dx: &[TV], dv: &[TV], h: T| {
+                    let x: Vec<TV> = (0..n).map(|i| x0[i] + h * dx[i]).collect();
+                    let v: Vec<TV> = (0..n).map(|i| v0[i] + h * dv[i]).collect();
+                    (x, v)
+                };
+
+                let k1 = (velocity.to_vec(), acceleration.to_vec());
+                let (x, v) = stage(position, velocity, &k1.0, &k1.1, 0.5 * dt);
+                let k2 = (v.clone(), evaluate(&x, &v));
+                let (x, v) = stage(position, velocity, &k2.0, &k2.1, 0.5 * dt);
+                let k3 = (v.clone(), evaluate(&x, &v));
+                let (x, v) = stage(position, velocity, &k3.0, &k3.1, dt);
+                let k4 = (v.clone(), evaluate(&x, &v));
+
+                for i in 0..n {
+                    position[i] += dt / 6. * (k1.0[i] + 2. * k2.0[i] + 2. * k3.0[i] + k4.0[i]);
+                    velocity[i] += dt / 6. * (k1.1[i] + 2. * k2.1[i] + 2. * k3.1[i] + k4.1[i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Simulates a simple harmonic oscillator with `omega = 1` for the given number of periods,
+    /// and returns the relative energy error after each step.
+    fn harmonic_oscillator_energy(integrator: Integrator, periods: usize) -> Vec<T> {
+        let steps_per_period = 64;
+        let dt = 2. * std::f64::consts::PI / steps_per_period as T;
+        let acceleration = |x: &[TV], _: &[TV]| x.iter().map(|x| -x).collect::<Vec<TV>>();
+        let energy = |x: &[TV], v: &[TV]| 0.5 * (x[0].norm_squared() + v[0].norm_squared());
+
+        let mut position = vec![TV::ith(0, 1.)];
+        let mut velocity = vec![TV::zeros()];
+        let initial_energy = energy(&position, &velocity);
+
+        (0..periods * steps_per_period)
+            .map(|_| {
+                let a = acceleration(&position, &velocity);
+                integrator.step(&mut position, &mut velocity, &a, dt, acceleration);
+                energy(&position, &velocity) / initial_energy - 1.
+            })
+            .collect()
+    }
+
+    fn max_abs(errors: &[T]) -> T {
+        errors.iter().map(|e| e.abs()).fold(0., T::max)
+    }
+
+    #[test]
+    fn test_symplectic_energy_is_bounded() {
+        for (integrator, bound) in [
+            (Integrator::SymplecticEuler, 0.1),
+            (Integrator::Leapfrog, 0.005),
+            (Integrator::VelocityVerlet, 0.005),
+        ] {
+            let errors = harmonic_oscillator_energy(integrator, 100);
+            let (first, rest) = errors.split_at(64);
+
+            // The error oscillates within a period, but does not grow over many periods.
+            assert!(max_abs(first) < bound, "{:?}", integrator);
+            assert!(max_abs(rest) <= 1.01 * max_abs(first), "{:?}", integrator);
+        }
+    }
+
+    #[test]
+    fn test_rk4_energy_decays() {
+        let errors = harmonic_oscillator_energy(Integrator::Rk4, 100);
+
+        // RK4 is very accurate, but the energy decreases in every step.
+        assert!(max_abs(&errors) < 1e-4);
+        assert!(errors.windows(2).all(|w| w[1] < w[0]));
+    }
+}
@@ -1 +1,5 @@
+pub mod conjugate_gradient;
+pub mod integrators;
 pub mod newtons_method;
+pub mod parallel;
+pub mod z_order;
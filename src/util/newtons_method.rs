@@ -15,10 +15,8 @@ use crate::math::*;
 
 pub fn solve_linear_system(a: &Mat, b: &TV) -> TV {
     let lu = a.lu();
-    lu.solve(b).expect(&format!(
-        "Unable to solve linear system. A = {:?}, b = {:?}",
-        a, b
-    ))
+    lu.solve(b)
+        .unwrap_or_else(|| panic!("Unable to solve linear system. A = {:?}, b = {:?}", a, b))
 }
 
 pub fn newtons_method<F, G>(func: F, grad: G, initial_guess: TV) -> TV
@@ -0,0 +1,107 @@
+//! Helpers for per-particle loops, which run in parallel with rayon when the `parallel` feature is
+//! enabled, and serially otherwise.
+//!
+//! Maps give bit-identical results to serial execution, since each element is computed
+//! independently by the same code. Floating point sums depend on the order of the additions, so
+//! [`sum`] only uses a parallel reduction when `deterministic` is false.
+
+use crate::math::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Computes `f(i)` for every `i` in `0..n`.
+pub fn map<R, F>(n: usize, f: F) -> Vec<R>
+where
+    R: Send,
+    F: Fn(usize) -> R + Send + Sync,
+{
+    #[cfg(feature = "parallel")]
+    return (0..n).into_par_iter().map(f).collect();
+
+    #[cfg(not(feature = "parallel"))]
+    (0..n).map(f).collect()
+}
+
+/// Calls `f(i, &mut values[i])` for every element of `values`.
+pub fn for_each_mut<X, F>(values: &mut [X], f: F)
+where
+    X: Send,
+    F: Fn(usize, &mut X) + Send + Sync,
+{
+    #[cfg(feature = "parallel")]
+    values.par_iter_mut().enumerate().for_each(|(i, x)| f(i, x));
+
+    #[cfg(not(feature = "parallel"))]
+    values.iter_mut().enumerate().for_each(|(i, x)| f(i, x));
+}
+
+/// Sums `values`, in order if `deterministic` is true.
+pub fn sum(values: &[T], deterministic: bool) -> T {
+    #[cfg(feature = "parallel")]
+    if !deterministic {
+        return values.par_iter().sum();
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    let _ = deterministic;
+
+    values.iter().sum()
+}
+
+#[cfg(all(test, feature = "parallel"))]
+mod tests {
+    use crate::base::Range;
+    use crate::math::*;
+    use crate::sph::{PressureSolver, SphParamaters, SphSimulation, SphSimulationBuilder};
+
+    fn dam_break(pressure_solver: PressureSolver) -> SphSimulation {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            delta_time: 4e-3,
+            gravity: TV::ith(1, -9.81),
+            pressure_solver,
+            deterministic: true,
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .boundary_walls()
+            .build()
+            .unwrap();
+
+        for _ in 0..10 {
+            sim.advance_timestep();
+        }
+        sim
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let solvers = [
+            SphParamaters::default().pressure_solver,
+            PressureSolver::Pcisph {
+                tolerance: 0.01,
+                max_iterations: 50,
+            },
+            PressureSolver::Iisph {
+                tolerance: 0.01,
+                max_iterations: 100,
+                omega: 0.5,
+            },
+        ];
+        for solver in solvers {
+            let run = |threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .unwrap()
+                    .install(|| dam_break(solver.clone()))
+            };
+            let (serial, parallel) = (run(1), run(4));
+
+            assert_eq!(serial.particles.position(), parallel.particles.position());
+            assert_eq!(serial.particles.velocity(), parallel.particles.velocity());
+            assert_eq!(serial.solver_stats, parallel.solver_stats);
+        }
+    }
+}
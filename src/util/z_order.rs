@@ -0,0 +1,189 @@
+//! Sorting particles along a Z-order (Morton) curve.
+//!
+//! Particles which are close together in space should be close together in memory, so that the
+//! neighbor loops access memory mostly sequentially. The Morton code of a grid cell interleaves
+//! the bits of its coordinates, and sorting the particles by the Morton code of their cell keeps
+//! nearby cells mostly contiguous in memory.
+
+use crate::base::Grid;
+use crate::math::*;
+
+/// The number of bits of each cell coordinate which fit into a 64 bit Morton code.
+const BITS_PER_AXIS: usize = 64 / DIM;
+
+/// Computes the Morton code of a cell by interleaving the bits of its coordinates. Negative
+/// coordinates are clamped to zero.
+pub fn morton_code(cell: IV) -> u64 {
+    let mut code = 0;
+    for bit in 0..BITS_PER_AXIS {
+        for a in 0..DIM {
+            let coordinate = cell[a].max(0) as u64;
+            code |= ((coordinate >> bit) & 1) << (bit * DIM + a);
+        }
+    }
+    code
+}
+
+/// A reordering of per-particle data. After applying it, element `i` is the element which was at
+/// index `self.source(i)`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Permutation {
+    source: Vec<usize>,
+}
+
+impl Permutation {
+    /// The identity permutation of `n` elements.
+    pub fn identity(n: usize) -> Self {
+        Self {
+            source: (0..n).collect(),
+        }
+    }
+
+    /// Sorts the points by the Morton code of their cell in `grid`. Points in the same cell keep
+    /// their relative order.
+    pub fn z_order(grid: &Grid, points: &[TV]) -> Self {
+        let codes: Vec<u64> = points
+            .iter()
+            .map(|&x| morton_code(grid.cell_index(x)))
+            .collect();
+
+        let mut source: Vec<usize> = (0..points.len()).collect();
+        source.sort_by_key(|&i| codes[i]);
+        Self { source }
+    }
+
+    /// The number of elements.
+    pub fn len(&self) -> usize {
+        self.source.len()
+    }
+
+    /// Returns true if there are no elements.
+    pub fn is_empty(&self) -> bool {
+        self.source.is_empty()
+    }
+
+    /// The old index of the element which is moved to index `i`.
+    pub fn source(&self, i: usize) -> usize {
+        self.source[i]
+    }
+
+    /// The old index of the element moved to each new index.
+    pub fn sources(&self) -> &[usize] {
+        &self.source
+    }
+
+    /// Returns true if applying the permutation does nothing.
+    pub fn is_identity(&self) -> bool {
+        self.source.iter().enumerate().all(|(i, &j)| i == j)
+    }
+
+    /// The new index of every element, so that `inverse()[old] == new`.
+    pub fn inverse(&self) -> Vec<usize> {
+        let mut inverse = vec![0; self.len()];
+        for (i, &j) in self.source.iter().enumerate() {
+            inverse[j] = i;
+        }
+        inverse
+    }
+
+    /// Reorders `data`, which must have one element per particle.
+    pub fn apply<X: Clone>(&self, data: &mut Vec<X>) {
+        assert_eq!(
+            data.len(),
+            self.len(),
+            "the data must have one element per particle"
+        );
+        *data = self.source.iter().map(|&j| data[j].clone()).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{Attribute, Range};
+    use crate::sph::{SphParamaters, SphSimulationBuilder};
+
+    #[test]
+    fn test_morton_code() {
+        assert_eq!(morton_code(IV::zeros()), 0);
+        assert_eq!(morton_code(IV::ith(0, 1)), 1);
+        assert_eq!(morton_code(IV::ith(1, 1)), 2);
+        assert_eq!(morton_code(IV::ith(0, 2)), 1 << DIM);
+        assert_eq!(morton_code(IV::from_element(3)), (1 << (2 * DIM)) - 1);
+    }
+
+    #[test]
+    fn test_z_order_permutation() {
+        let grid = Grid::new(
+            IV::from_element(4),
+            Range::new(TV::zeros(), TV::from_element(4.)),
+        );
+        let points = vec![
+            TV::from_element(3.5),
+            TV::from_element(0.5),
+            TV::ith(1, 0.5),
+            TV::from_element(1.5),
+        ];
+        let permutation = Permutation::z_order(&grid, &points);
+        assert_eq!(permutation.inverse()[1], 0);
+        assert_eq!(permutation.inverse()[0], 3);
+
+        let mut sorted = points.clone();
+        permutation.apply(&mut sorted);
+        let codes: Vec<u64> = sorted
+            .iter()
+            .map(|&x| morton_code(grid.cell_index(x)))
+            .collect();
+        assert!(codes.windows(2).all(|w| w[0] <= w[1]));
+
+        let inverse = permutation.inverse();
+        for (old, &x) in points.iter().enumerate() {
+            assert_eq!(sorted[inverse[old]], x);
+        }
+    }
+
+    #[test]
+    fn test_simulation_reordering() {
+        let params = SphParamaters {
+            domain: Range::new(TV::zeros(), TV::from_element(0.5)),
+            reorder_interval: Some(2),
+            ..Default::default()
+        };
+        let mut sim = SphSimulationBuilder::new(params)
+            .fill_box(Range::new(TV::zeros(), TV::from_element(0.1)))
+            .build()
+            .unwrap();
+
+        // Per-particle data kept by the user, which must follow the particles.
+        let mut ids: Vec<usize> = (0..sim.particles.len()).collect();
+        const ID: Attribute<usize> = Attribute::new("id");
+        let attributes = sim.particles.data_mut();
+        attributes.add_attribute(ID, 0).unwrap();
+        attributes.get_mut(ID).unwrap().copy_from_slice(&ids);
+        let mut reorderings = 0;
+        for _ in 0..4 {
+            let before = sim.particles.position().to_vec();
+            sim.advance_timestep();
+            if let Some(permutation) = &sim.last_reordering {
+                permutation.apply(&mut ids);
+                reorderings += 1;
+            }
+            if sim.dt_history.len() == 1 {
+                let permutation = sim.last_reordering.as_ref().unwrap();
+                assert!(!permutation.is_identity());
+                for (i, &id) in ids.iter().enumerate() {
+                    assert_eq!(permutation.source(i), id);
+                    // The particles only move a small fraction of their spacing in one step.
+                    let dx = sim.particles.position()[i] - before[id];
+                    assert!(dx.norm() < 0.1 * sim.params.h);
+                }
+            }
+        }
+        assert_eq!(reorderings, 2);
+        assert_eq!(sim.particles.data().get(ID).unwrap(), ids);
+
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..ids.len()).collect::<Vec<_>>());
+    }
+}